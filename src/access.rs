@@ -0,0 +1,260 @@
+// Списки доступа к игровому серверу.
+//
+// Файл читается при старте и перечитывается по команде админки или по SIGHUP.
+// Формат построчный, # начинает комментарий:
+//
+//   ban 96a9354f-a8bc-4895-8317-61bf73f127c8
+//   ban 10.0.0.0/8
+//   allow 192.168.1.0/24
+//   allow fc5d5532-d8c1-4a7d-b035-c2edc8ba66c6
+//
+// Бан всегда важнее разрешения. Если в файле есть хотя бы одно allow для подписей,
+// то пускаем только перечисленные подписи, и так же отдельно для адресов.
+
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+use crate::protocol::AccessDenied;
+
+// Сеть в нотации CIDR. Адрес без маски считается сетью из одного адреса
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Клиент по IPv6 сокету может прийти как ::ffff:1.2.3.4, сравниваем его как IPv4
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+// Совпадают ли первые prefix бит
+fn prefix_matches(network: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let full_bytes = prefix as usize / 8;
+    let rest_bits = prefix % 8;
+
+    if network[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+    if rest_bits == 0 {
+        return true;
+    }
+
+    let mask = 0xffu8 << (8 - rest_bits);
+    network[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+impl std::str::FromStr for Network {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| format!("{} is not a valid address", value))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .ok_or_else(|| format!("{} has invalid prefix length", value))?,
+            None => max_prefix,
+        };
+
+        Ok(Network { addr, prefix })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    banned_signatures: HashSet<uuid::Uuid>,
+    banned_networks: Vec<Network>,
+    allowed_signatures: HashSet<uuid::Uuid>,
+    allowed_networks: Vec<Network>,
+}
+
+impl AccessList {
+    pub fn new() -> Self {
+        AccessList::default()
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut list = AccessList::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let error = |e: String| format!("line {}: {}", number + 1, e);
+            let (action, target) = line.split_once(char::is_whitespace).ok_or_else(|| {
+                error(format!(
+                    "expected \"ban\" or \"allow\" and a target in {}",
+                    line
+                ))
+            })?;
+            let target = target.trim();
+            let signature = uuid::Uuid::parse_str(target).ok();
+
+            match (action, signature) {
+                ("ban", Some(signature)) => {
+                    list.banned_signatures.insert(signature);
+                }
+                ("allow", Some(signature)) => {
+                    list.allowed_signatures.insert(signature);
+                }
+                ("ban", None) => list.banned_networks.push(target.parse().map_err(error)?),
+                ("allow", None) => list.allowed_networks.push(target.parse().map_err(error)?),
+                (other, _) => return Err(error(format!("unknown action {}", other))),
+            }
+        }
+
+        Ok(list)
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        AccessList::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    // Бан и разбан подписи из админки в уже загруженных списках
+    pub fn set_banned(&mut self, signature: uuid::Uuid, banned: bool) {
+        if banned {
+            self.banned_signatures.insert(signature);
+        } else {
+            self.banned_signatures.remove(&signature);
+        }
+    }
+
+    // Тот же бан в файле, иначе он пропал бы при перезапуске и при первом же
+    // перечитывании списков. Остальные строки файла не трогаем
+    pub fn save_ban(path: &str, signature: uuid::Uuid, banned: bool) -> Result<(), String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let bans_signature = |line: &str| {
+            let line = line.split('#').next().unwrap_or("").trim();
+            line.split_once(char::is_whitespace)
+                .is_some_and(|(action, target)| {
+                    action == "ban" && uuid::Uuid::parse_str(target.trim()) == Ok(signature)
+                })
+        };
+
+        let mut lines = text
+            .lines()
+            .filter(|line| !bans_signature(line))
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        if banned {
+            lines.push(format!("ban {}", signature));
+        }
+        std::fs::write(path, lines.join("\n") + "\n").map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn check(&self, signature: uuid::Uuid, ip: IpAddr) -> Result<(), AccessDenied> {
+        if self.banned_signatures.contains(&signature) {
+            return Err(AccessDenied::SignatureBanned);
+        }
+        if self
+            .banned_networks
+            .iter()
+            .any(|network| network.contains(ip))
+        {
+            return Err(AccessDenied::AddressBanned);
+        }
+
+        let signature_allowed =
+            self.allowed_signatures.is_empty() || self.allowed_signatures.contains(&signature);
+        let address_allowed = self.allowed_networks.is_empty()
+            || self
+                .allowed_networks
+                .iter()
+                .any(|network| network.contains(ip));
+        if !signature_allowed || !address_allowed {
+            return Err(AccessDenied::NotAllowed);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_network_contains() {
+        let network = "10.1.128.0/17".parse::<Network>().unwrap();
+        assert!(network.contains("10.1.200.7".parse().unwrap()));
+        assert!(!network.contains("10.1.127.255".parse().unwrap()));
+        assert!(network.contains("::ffff:10.1.128.1".parse().unwrap()));
+
+        let single = "2001:db8::1".parse::<Network>().unwrap();
+        assert!(single.contains("2001:db8::1".parse().unwrap()));
+        assert!(!single.contains("2001:db8::2".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("localhost".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn test_access_list_check() {
+        let banned = uuid::Uuid::new_v4();
+        let allowed = uuid::Uuid::new_v4();
+        let list = AccessList::parse(&format!(
+            "# test\nban {}\nban 10.0.0.0/8\nallow 0.0.0.0/0 # everyone\nallow {}\n",
+            banned, allowed
+        ))
+        .unwrap();
+
+        let home = "192.168.1.10".parse().unwrap();
+        assert_eq!(list.check(allowed, home), Ok(()));
+        assert_eq!(list.check(banned, home), Err(AccessDenied::SignatureBanned));
+        assert_eq!(
+            list.check(allowed, "10.2.3.4".parse().unwrap()),
+            Err(AccessDenied::AddressBanned)
+        );
+        assert_eq!(
+            list.check(uuid::Uuid::new_v4(), home),
+            Err(AccessDenied::NotAllowed)
+        );
+
+        assert!(AccessList::parse("deny 10.0.0.0/8").is_err());
+
+        // Бан из админки переживает перечитывание файла, а разбан убирает и строку из файла
+        let path = std::env::temp_dir().join(format!("nltt-access-{}", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, format!("allow 0.0.0.0/0\nban {} # cheater\n", banned)).unwrap();
+        let mut list = AccessList::load(path).unwrap();
+        AccessList::save_ban(path, allowed, true).unwrap();
+        list.set_banned(allowed, true);
+        AccessList::save_ban(path, banned, false).unwrap();
+        list.set_banned(banned, false);
+        let reloaded = AccessList::load(path).unwrap();
+        assert_eq!(
+            reloaded.check(allowed, home),
+            Err(AccessDenied::SignatureBanned)
+        );
+        assert_eq!(reloaded.check(banned, home), Ok(()));
+        assert_eq!(
+            list.check(allowed, home),
+            Err(AccessDenied::SignatureBanned)
+        );
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(AccessList::new().check(uuid::Uuid::new_v4(), home), Ok(()));
+    }
+}
@@ -0,0 +1,70 @@
+// Команды админки из аргументов командной строки, общие для nltt-admin и nltt admin.
+
+use crate::protocol::AdminCommand;
+
+pub const USAGE: &str = "usage: nltt-admin peers | kick <player_id> | ban <player_id> | unban <player_id> | notice <text> | reset-stats | reload-access | set <LIMIT> [value]";
+
+// Разбираем аргументы в команду. Лимит без значения снимается.
+// Ошибка это текст для пользователя: USAGE или что не так с аргументом
+pub fn parse_command(args: &[String]) -> Result<AdminCommand, String> {
+    let player_id = |index: usize| {
+        let player_id = args.get(index).ok_or_else(|| USAGE.to_string())?;
+        uuid::Uuid::parse_str(player_id)
+            .map_err(|_| format!("{} is not a valid player id", player_id))
+    };
+
+    let Some(command) = args.first() else {
+        return Err(USAGE.to_string());
+    };
+    match command.as_str() {
+        "peers" => Ok(AdminCommand::ListPeers),
+        "kick" => Ok(AdminCommand::Kick {
+            player_id: player_id(1)?,
+        }),
+        "ban" => Ok(AdminCommand::Ban {
+            player_id: player_id(1)?,
+        }),
+        "unban" => Ok(AdminCommand::Unban {
+            player_id: player_id(1)?,
+        }),
+        "notice" if args.len() > 1 => Ok(AdminCommand::Notice {
+            text: args[1..].join(" "),
+        }),
+        "reset-stats" => Ok(AdminCommand::ResetStats),
+        "reload-access" => Ok(AdminCommand::ReloadAccessList),
+        "set" if args.len() > 1 => Ok(AdminCommand::SetLimit {
+            name: args[1].clone(),
+            value: args.get(2).cloned(),
+        }),
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let player_id = uuid::Uuid::new_v4();
+
+        assert_eq!(
+            parse_command(&args(&["ban", &player_id.to_string()])),
+            Ok(AdminCommand::Ban { player_id })
+        );
+        assert_eq!(
+            parse_command(&args(&["set", "FLASH_BURST"])),
+            Ok(AdminCommand::SetLimit {
+                name: "FLASH_BURST".to_string(),
+                value: None
+            })
+        );
+        assert_eq!(
+            parse_command(&args(&["kick", "pupa"])),
+            Err("pupa is not a valid player id".to_string())
+        );
+        assert_eq!(parse_command(&args(&["kick"])), Err(USAGE.to_string()));
+        assert_eq!(parse_command(&[]), Err(USAGE.to_string()));
+    }
+}
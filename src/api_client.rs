@@ -0,0 +1,403 @@
+// Клиент API сервера.
+//
+// Раньше каждая утилита статистики сама открывала соединение, отправляла ключ и
+// разбирала фреймы ответа. Здесь это сделано один раз, а наружу торчат обычные
+// структуры, так что статистику можно читать из любого сервиса без протокольного кода.
+//
+// API сервер отвечает на один запрос и закрывает соединение, поэтому каждый
+// метод открывает свое соединение и читает ответ до закрытия.
+
+use std::time::Duration;
+
+use futures::SinkExt;
+use tokio_stream::StreamExt;
+
+use crate::protocol::{AdminCommand, ErrorReason, PupaCodec, PupaFrame};
+
+#[derive(Debug)]
+pub enum ApiError {
+    Io(std::io::Error),
+    // Ключа нет или он неизвестен серверу
+    Unauthorized,
+    // Сервер отказал в запросе, например ключу не хватает прав (Forbidden)
+    Rejected(ErrorReason),
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::Io(e) => write!(f, "API connection failed: {}", e),
+            ApiError::Unauthorized => write!(f, "API server requires a valid API key"),
+            ApiError::Rejected(reason) => {
+                write!(f, "API server rejected the request: {:?}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<std::io::Error> for ApiError {
+    fn from(e: std::io::Error) -> Self {
+        ApiError::Io(e)
+    }
+}
+
+// Какую таблицу победителей читать
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum WinnersQuery {
+    // Живая таблица по всему серверу
+    #[default]
+    Live,
+    Room(String),
+    // Итоговая таблица завершенного сезона
+    Season(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Winner {
+    pub player_id: uuid::Uuid,
+    pub wins: u32,
+    pub score: u64,
+    // В архиве сезона онлайн не хранится
+    pub online: Option<bool>,
+    // Подробности есть только в живой таблице по всему серверу
+    pub stats: Option<PlayerStats>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayerStats {
+    pub name: Option<String>,
+    pub rating: u32,
+    pub messages_received: u32,
+    pub messages_sent: u32,
+    pub early_flashes: u32,
+    pub invalid_content: u32,
+    pub rtt_ms: Option<u64>,
+}
+
+// Фильтры лога побед. Сервер отдает лог целиком, фильтруем уже на клиенте
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct WinLogQuery {
+    pub player_id: Option<uuid::Uuid>,
+    pub room: Option<String>,
+    // Только победы не раньше этого unix времени в миллисекундах
+    pub since: Option<u128>,
+    // Сколько последних записей оставить
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WinLogEntry {
+    pub player_id: uuid::Uuid,
+    pub timestamp: u128,
+    pub msg_id: uuid::Uuid,
+    pub latency: Duration,
+    pub room: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayerProfile {
+    pub player_id: uuid::Uuid,
+    pub name: Option<String>,
+    pub avatar: Option<String>,
+}
+
+// Игрок в списке peers админки. Подпись видна только здесь, ключу с правом admin
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub player_id: uuid::Uuid,
+    pub name: Option<String>,
+    pub signature: uuid::Uuid,
+    pub address: String,
+    pub online: bool,
+    pub room: String,
+    pub banned: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminReply {
+    // Ответ на ListPeers, сначала онлайн игроки
+    Peers(Vec<PeerInfo>),
+    // Ответ на остальные команды
+    Done { ok: bool, message: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct ApiClient {
+    server_addr: String,
+    // Без ключа доступно только то, что сервер отдает без ключей
+    key: Option<String>,
+}
+
+impl ApiClient {
+    pub fn new(server_addr: &str, key: Option<String>) -> Self {
+        ApiClient {
+            server_addr: server_addr.to_string(),
+            key,
+        }
+    }
+
+    pub async fn winners(&self, query: WinnersQuery) -> Result<Vec<Winner>, ApiError> {
+        let request = match query {
+            WinnersQuery::Live => PupaFrame::ShowWinners,
+            WinnersQuery::Room(room) => PupaFrame::ShowRoomWinners { room },
+            WinnersQuery::Season(season) => PupaFrame::ShowSeasonWinners { season },
+        };
+
+        let winners = self
+            .request(request)
+            .await?
+            .into_iter()
+            .filter_map(|frame| match frame {
+                PupaFrame::WinnerRecord {
+                    player_id,
+                    name,
+                    online,
+                    wins,
+                    score,
+                    rating,
+                    messages_received,
+                    messages_sent,
+                    early_flashes,
+                    invalid_content,
+                    rtt_ms,
+                } => Some(Winner {
+                    player_id,
+                    wins,
+                    score,
+                    online: Some(online),
+                    stats: Some(PlayerStats {
+                        name,
+                        rating,
+                        messages_received,
+                        messages_sent,
+                        early_flashes,
+                        invalid_content,
+                        rtt_ms,
+                    }),
+                }),
+                PupaFrame::RoomWinnerRecord {
+                    player_id,
+                    online,
+                    wins,
+                    score,
+                    ..
+                } => Some(Winner {
+                    player_id,
+                    wins,
+                    score,
+                    online: Some(online),
+                    stats: None,
+                }),
+                PupaFrame::SeasonWinnerRecord {
+                    player_id,
+                    wins,
+                    score,
+                    ..
+                } => Some(Winner {
+                    player_id,
+                    wins,
+                    score,
+                    online: None,
+                    stats: None,
+                }),
+                _ => None,
+            })
+            .collect();
+
+        Ok(winners)
+    }
+
+    pub async fn win_log(&self, query: WinLogQuery) -> Result<Vec<WinLogEntry>, ApiError> {
+        let mut entries = self
+            .request(PupaFrame::ShowWinnersLog)
+            .await?
+            .into_iter()
+            .filter_map(|frame| match frame {
+                PupaFrame::WinLogRecord {
+                    player_id,
+                    timestamp,
+                    msg_id,
+                    latency_ms,
+                    room,
+                } => Some(WinLogEntry {
+                    player_id,
+                    timestamp,
+                    msg_id,
+                    latency: Duration::from_millis(latency_ms),
+                    room,
+                }),
+                _ => None,
+            })
+            .filter(|entry| query.player_id.is_none_or(|id| id == entry.player_id))
+            .filter(|entry| query.room.as_ref().is_none_or(|room| *room == entry.room))
+            .filter(|entry| query.since.is_none_or(|since| entry.timestamp >= since))
+            .collect::<Vec<_>>();
+
+        if let Some(limit) = query.limit {
+            entries.drain(..entries.len().saturating_sub(limit));
+        }
+
+        Ok(entries)
+    }
+
+    // None, если игрока с таким публичным id сервер не знает
+    pub async fn player(&self, player_id: uuid::Uuid) -> Result<Option<PlayerProfile>, ApiError> {
+        match self.request(PupaFrame::ShowProfile { player_id }).await {
+            Ok(frames) => Ok(frames.into_iter().find_map(|frame| match frame {
+                PupaFrame::Profile {
+                    player_id,
+                    name,
+                    avatar,
+                } => Some(PlayerProfile {
+                    player_id,
+                    name,
+                    avatar,
+                }),
+                _ => None,
+            })),
+            Err(ApiError::Rejected(ErrorReason::UnknownPlayer)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Для админских команд нужен ключ с правом admin (или ADMIN_TOKEN сервера)
+    pub async fn admin(&self, command: AdminCommand) -> Result<AdminReply, ApiError> {
+        let mut peers = Vec::new();
+        for frame in self.request(PupaFrame::Admin { command }).await? {
+            match frame {
+                PupaFrame::AdminResult { ok, message } => {
+                    return Ok(AdminReply::Done { ok, message })
+                }
+                PupaFrame::PeerRecord {
+                    player_id,
+                    name,
+                    signature,
+                    address,
+                    online,
+                    room,
+                    banned,
+                } => peers.push(PeerInfo {
+                    player_id,
+                    name,
+                    signature,
+                    address,
+                    online,
+                    room,
+                    banned,
+                }),
+                _ => {}
+            }
+        }
+
+        Ok(AdminReply::Peers(peers))
+    }
+
+    // Отправляет запрос и собирает все фреймы ответа, пока сервер не закроет соединение
+    async fn request(&self, request: PupaFrame) -> Result<Vec<PupaFrame>, ApiError> {
+        let stream = tokio::net::TcpStream::connect(&self.server_addr).await?;
+        let mut framed = tokio_util::codec::Framed::new(stream, PupaCodec::new());
+
+        if let Some(key) = &self.key {
+            framed
+                .send(PupaFrame::ApiAuthorize { key: key.clone() })
+                .await?;
+        }
+        framed.send(request).await?;
+
+        let mut frames = Vec::new();
+        while let Some(frame) = framed.next().await {
+            match frame? {
+                PupaFrame::NonAuthorized => return Err(ApiError::Unauthorized),
+                PupaFrame::Error { reason, .. } => return Err(ApiError::Rejected(reason)),
+                frame => frames.push(frame),
+            }
+        }
+
+        Ok(frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // API сервер на один запрос: проверяет ключ и отдает заготовленный ответ
+    async fn serve_once(response: Vec<PupaFrame>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap().to_string();
+
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut framed = tokio_util::codec::Framed::new(socket, PupaCodec::new());
+            let authorize = framed.next().await.unwrap().unwrap();
+            assert_eq!(
+                authorize,
+                PupaFrame::ApiAuthorize {
+                    key: "key".to_string()
+                }
+            );
+            framed.next().await.unwrap().unwrap();
+            for frame in response {
+                framed.send(frame).await.unwrap();
+            }
+        });
+
+        server_addr
+    }
+
+    #[tokio::test]
+    async fn test_win_log_is_filtered_on_client() {
+        let alice = uuid::Uuid::new_v4();
+        let bob = uuid::Uuid::new_v4();
+        let record = |player_id, timestamp| PupaFrame::WinLogRecord {
+            player_id,
+            timestamp,
+            msg_id: uuid::Uuid::new_v4(),
+            latency_ms: 1_300,
+            room: "lobby".to_string(),
+        };
+        let server_addr = serve_once(vec![
+            record(alice, 1),
+            record(bob, 2),
+            record(alice, 3),
+            record(alice, 4),
+        ])
+        .await;
+
+        let entries = ApiClient::new(&server_addr, Some("key".to_string()))
+            .win_log(WinLogQuery {
+                player_id: Some(alice),
+                limit: Some(2),
+                ..WinLogQuery::default()
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| entry.timestamp)
+                .collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+        assert_eq!(entries[0].latency, Duration::from_millis(1_300));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_player_is_none() {
+        let server_addr = serve_once(vec![PupaFrame::Error {
+            msg_id: None,
+            reason: ErrorReason::UnknownPlayer,
+        }])
+        .await;
+
+        let profile = ApiClient::new(&server_addr, Some("key".to_string()))
+            .player(uuid::Uuid::new_v4())
+            .await
+            .unwrap();
+
+        assert_eq!(profile, None);
+    }
+}
@@ -0,0 +1,123 @@
+// Ключи доступа к API серверу.
+//
+// Раньше API отдавал всем все, а ведь WinnerRecord содержит подписи игроков, которые
+// одновременно и их пароли. Теперь клиент первым фреймом присылает ApiAuthorize с ключом,
+// а у ключа есть набор прав. Ключи лежат в файле API_KEYS_FILE, по одному на строку:
+//
+//   3f1c0a9e-stats-dashboard read:leaderboard read:winlog
+//   b7d2e5aa-ops admin
+//
+// admin разрешает все. Если ключей на сервере нет совсем, API открыт только на чтение,
+// как и раньше, с любым ключом или без него, а админка выключена.
+
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    // Таблицы победителей, комнаты, сезоны и задержки
+    ReadLeaderboard,
+    // Лог побед
+    ReadWinlog,
+    // Команды администратора
+    Admin,
+}
+
+impl Scope {
+    // Права, которые есть у любого клиента, когда на сервере не настроено ни одного ключа
+    pub const OPEN: [Scope; 2] = [Scope::ReadLeaderboard, Scope::ReadWinlog];
+}
+
+impl std::str::FromStr for Scope {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "read:leaderboard" => Ok(Scope::ReadLeaderboard),
+            "read:winlog" => Ok(Scope::ReadWinlog),
+            "admin" => Ok(Scope::Admin),
+            other => Err(format!("unknown scope {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ApiKeys {
+    keys: HashMap<String, HashSet<Scope>>,
+}
+
+impl ApiKeys {
+    pub fn new() -> Self {
+        ApiKeys::default()
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut api_keys = ApiKeys::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut parts = line.split_whitespace();
+            let key = parts.next().unwrap_or_default();
+            let scopes = parts
+                .map(|scope| scope.parse::<Scope>())
+                .collect::<Result<HashSet<_>, _>>()
+                .map_err(|e| format!("line {}: {}", number + 1, e))?;
+            if scopes.is_empty() {
+                return Err(format!("line {}: key without scopes", number + 1));
+            }
+
+            api_keys.insert(key, scopes);
+        }
+
+        Ok(api_keys)
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        ApiKeys::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn insert(&mut self, key: &str, scopes: HashSet<Scope>) {
+        self.keys.entry(key.to_string()).or_default().extend(scopes);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    // Права ключа или None, если такого ключа нет
+    pub fn scopes(&self, key: &str) -> Option<&HashSet<Scope>> {
+        self.keys.get(key)
+    }
+}
+
+// Хватает ли набора прав для действия
+pub fn allows(scopes: &HashSet<Scope>, scope: Scope) -> bool {
+    scopes.contains(&scope) || scopes.contains(&Scope::Admin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_api_keys() {
+        let api_keys =
+            ApiKeys::parse("# keys\ndashboard read:leaderboard read:winlog\nops admin\n").unwrap();
+
+        let dashboard = api_keys.scopes("dashboard").unwrap();
+        assert!(allows(dashboard, Scope::ReadWinlog));
+        assert!(!allows(dashboard, Scope::Admin));
+        assert!(allows(
+            api_keys.scopes("ops").unwrap(),
+            Scope::ReadLeaderboard
+        ));
+        assert!(api_keys.scopes("unknown").is_none());
+
+        assert!(ApiKeys::parse("lonely").is_err());
+        assert!(ApiKeys::parse("key write:everything").is_err());
+    }
+}
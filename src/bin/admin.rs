@@ -0,0 +1,50 @@
+use std::env;
+use std::error::Error;
+
+use nltt::admin::parse_command;
+use nltt::api_client::{AdminReply, ApiClient};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let command = match parse_command(&args) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let api_server_port = env::var("API_SERVER_PORT")
+        .expect("API_SERVER_PORT environment variable not set")
+        .parse::<u32>()
+        .expect("API_SERVER_PORT  environment variable is not a valid number");
+    // Ключ с правом admin, ADMIN_TOKEN сервера тоже подходит
+    let key = env::var("API_KEY")
+        .or_else(|_| env::var("ADMIN_TOKEN"))
+        .expect("API_KEY or ADMIN_TOKEN environment variable not set");
+
+    let server_addr = format!("127.0.0.1:{}", &api_server_port);
+    match ApiClient::new(&server_addr, Some(key)).admin(command).await {
+        Ok(AdminReply::Peers(peers)) => {
+            for peer in peers {
+                println!(
+                    "Player: {}, name: {}, signature: {}, address: {}, online: {}, room: {}, banned: {}",
+                    peer.player_id, peer.name.as_deref().unwrap_or("-"), peer.signature, peer.address, peer.online, peer.room, peer.banned
+                );
+            }
+        }
+        Ok(AdminReply::Done { ok, message }) => {
+            println!("{}", message);
+            if !ok {
+                std::process::exit(1);
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use std::env;
+use std::error::Error;
+
+use futures::SinkExt;
+use tokio_stream::StreamExt;
+
+use nltt::protocol;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let api_server_port = env::var("API_SERVER_PORT")
+        .expect("API_SERVER_PORT environment variable not set")
+        .parse::<u32>()
+        .expect("API_SERVER_PORT  environment variable is not a valid number");
+
+    let server_addr = format!("127.0.0.1:{}", &api_server_port);
+
+    println!("Connecting to {} ...", &server_addr);
+
+    let stream = tokio::net::TcpStream::connect(server_addr.clone()).await?;
+    let mut framed = tokio_util::codec::Framed::new(stream, protocol::PupaCodec::new());
+
+    println!("Established connection to {}", server_addr);
+
+    // Ключ нужен, если на сервере настроены API ключи
+    if let Ok(key) = env::var("API_KEY") {
+        framed
+            .send(protocol::PupaFrame::ApiAuthorize { key })
+            .await?;
+    }
+
+    let frame = protocol::PupaFrame::ShowLatencies;
+
+    framed.send(frame).await?;
+
+    while let Some(result) = framed.next().await {
+        match result {
+            Ok(protocol::PupaFrame::LatencyRecord {
+                player_id,
+                samples,
+                min_ms,
+                median_ms,
+                p99_ms,
+            }) => {
+                println!(
+                    "Player: {}, samples: {}, min_ms: {}, median_ms: {}, p99_ms: {}",
+                    player_id, samples, min_ms, median_ms, p99_ms
+                );
+            }
+            Ok(protocol::PupaFrame::NonAuthorized) => {
+                eprintln!("API server requires a valid API_KEY");
+            }
+            Ok(protocol::PupaFrame::Error { reason, .. }) => {
+                eprintln!("API server rejected the request: {:?}", reason);
+            }
+            _ => {
+                // ignore
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,10 +1,7 @@
 use std::env;
 use std::error::Error;
 
-use futures::SinkExt;
-use tokio_stream::StreamExt;
-
-use nltt::protocol;
+use nltt::api_client::{ApiClient, WinnersQuery};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -14,35 +11,64 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .expect("API_SERVER_PORT  environment variable is not a valid number");
 
     let server_addr = format!("127.0.0.1:{}", &api_server_port);
+    // Ключ нужен, если на сервере настроены API ключи
+    let api = ApiClient::new(&server_addr, env::var("API_KEY").ok());
 
-    println!("Connecting to {} ...", &server_addr);
-
-    let stream = tokio::net::TcpStream::connect(server_addr.clone()).await?;
-    let mut framed = tokio_util::codec::Framed::new(stream, protocol::PupaCodec::new());
-
-    println!("Established connection to {}", server_addr);
+    // С PLAYER печатаем профиль одного игрока
+    if let Ok(player_id) = env::var("PLAYER") {
+        let player_id = uuid::Uuid::parse_str(&player_id).expect("uuid should be valid");
+        match api.player(player_id).await? {
+            Some(profile) => println!(
+                "Player: {}, name: {}, avatar: {}",
+                profile.player_id,
+                profile.name.as_deref().unwrap_or("-"),
+                profile.avatar.as_deref().unwrap_or("-")
+            ),
+            None => eprintln!("Player {} is unknown", player_id),
+        }
 
-    let frame = protocol::PupaFrame::ShowWinners;
+        return Ok(());
+    }
 
-    framed.send(frame).await?;
+    // С ROOM печатаем таблицу одной комнаты, с SEASON итоговую таблицу завершенного сезона,
+    // без них общую живую таблицу по всему серверу
+    let query = if let Ok(room) = env::var("ROOM") {
+        WinnersQuery::Room(room)
+    } else if let Ok(season) = env::var("SEASON") {
+        WinnersQuery::Season(
+            season
+                .parse::<u32>()
+                .expect("SEASON environment variable is not a valid number"),
+        )
+    } else {
+        WinnersQuery::Live
+    };
 
-    while let Some(result) = framed.next().await {
-        match result {
-            Ok(protocol::PupaFrame::WinnerRecord {
-                signature,
-                online,
-                wins,
-                messages_received,
-                messages_sent,
-            }) => {
+    for winner in api.winners(query.clone()).await? {
+        match (&query, winner.stats) {
+            (WinnersQuery::Room(room), _) => println!(
+                "Room: {}, player: {}, online: {}, wins: {}, score: {}",
+                room,
+                winner.player_id,
+                winner.online.unwrap_or_default(),
+                winner.wins,
+                winner.score
+            ),
+            (WinnersQuery::Season(season), _) => println!(
+                "Season: {}, player: {}, wins: {}, score: {}",
+                season, winner.player_id, winner.wins, winner.score
+            ),
+            (WinnersQuery::Live, Some(stats)) => {
+                let rtt_ms = stats
+                    .rtt_ms
+                    .map(|rtt_ms| rtt_ms.to_string())
+                    .unwrap_or_else(|| "-".to_string());
                 println!(
-                    "Signature: {}, online: {}, wins: {}, messages_received: {}, messages_sent: {}",
-                    signature, online, wins, messages_received, messages_sent
+                    "Player: {}, name: {}, online: {}, wins: {}, score: {}, rating: {}, messages_received: {}, messages_sent: {}, early_flashes: {}, invalid_content: {}, rtt_ms: {}",
+                    winner.player_id, stats.name.as_deref().unwrap_or("-"), winner.online.unwrap_or_default(), winner.wins, winner.score, stats.rating, stats.messages_received, stats.messages_sent, stats.early_flashes, stats.invalid_content, rtt_ms
                 );
             }
-            _ => {
-                // ignore
-            }
+            (WinnersQuery::Live, None) => {}
         }
     }
 
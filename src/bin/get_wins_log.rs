@@ -1,10 +1,7 @@
 use std::env;
 use std::error::Error;
 
-use futures::SinkExt;
-use tokio_stream::StreamExt;
-
-use nltt::protocol;
+use nltt::api_client::{ApiClient, WinLogQuery};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -14,34 +11,32 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .expect("API_SERVER_PORT  environment variable is not a valid number");
 
     let server_addr = format!("127.0.0.1:{}", &api_server_port);
-
-    println!("Connecting to {} ...", &server_addr);
-
-    let stream = tokio::net::TcpStream::connect(server_addr.clone()).await?;
-    let mut framed = tokio_util::codec::Framed::new(stream, protocol::PupaCodec::new());
-
-    println!("Established connection to {}", server_addr);
-
-    let frame = protocol::PupaFrame::ShowWinnersLog;
-
-    framed.send(frame).await?;
-
-    while let Some(result) = framed.next().await {
-        match result {
-            Ok(protocol::PupaFrame::WinLogRecord {
-                signature,
-                timestamp,
-                msg_id,
-            }) => {
-                println!(
-                    "Signature: {}, timestamp: {}, msg_id: {}",
-                    signature, timestamp, msg_id
-                );
-            }
-            _ => {
-                // ignore
-            }
-        }
+    // Ключ нужен, если на сервере настроены API ключи
+    let api = ApiClient::new(&server_addr, env::var("API_KEY").ok());
+
+    // PLAYER, ROOM и LIMIT сужают лог до побед одного игрока, одной комнаты и последних записей
+    let query = WinLogQuery {
+        player_id: env::var("PLAYER")
+            .ok()
+            .map(|player_id| uuid::Uuid::parse_str(&player_id).expect("uuid should be valid")),
+        room: env::var("ROOM").ok(),
+        since: None,
+        limit: env::var("LIMIT").ok().map(|limit| {
+            limit
+                .parse()
+                .expect("LIMIT environment variable is not a valid number")
+        }),
+    };
+
+    for entry in api.win_log(query).await? {
+        println!(
+            "Player: {}, timestamp: {}, msg_id: {}, latency_ms: {}, room: {}",
+            entry.player_id,
+            entry.timestamp,
+            entry.msg_id,
+            entry.latency.as_millis(),
+            entry.room
+        );
     }
 
     Ok(())
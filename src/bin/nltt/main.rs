@@ -0,0 +1,291 @@
+// Один бинарь на все: сервер, клиент, статистика и админка.
+//
+// Сервер и клиент те же, что в отдельных бинарях, и настраиваются так же через env.
+// Команды статистики берут параметры из флагов и печатают ответ таблицей, CSV или JSON,
+// чтобы его можно было отдать дальше в инструменты анализа.
+
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+
+use nltt::api_client::{AdminReply, ApiClient, WinLogQuery, WinnersQuery};
+use nltt::output::{Format, Table, Value};
+use nltt::{admin, client, server};
+
+const USAGE: &str = "usage: nltt <command> [options]
+
+commands:
+  server                                     game, API and metrics servers, configured through env
+  client [client flags]                      bot, see `nltt client --help`
+  winners [--room <room> | --season <n>]     live, room or season leaderboard
+  winlog [--player <id>] [--room <room>] [--since <unix ms>] [--limit <n>]
+  player <player_id>                         player profile
+  admin <command> [args]                     admin commands, `nltt admin` lists them
+
+options of winners, winlog, player and admin:
+  --format table|json|csv                    output format, table by default
+  --api-port <port>                          API server port, API_SERVER_PORT by default
+  --key <key>                                API key, API_KEY (or ADMIN_TOKEN for admin) by default";
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let Some((command, args)) = args.split_first() else {
+        usage();
+    };
+
+    let result = match command.as_str() {
+        "server" => {
+            env_logger::init();
+            runtime().block_on(server::run());
+            Ok(())
+        }
+        "client" => {
+            let Some(strategy) = client::parse_strategy(args) else {
+                eprintln!("{}", client::USAGE);
+                std::process::exit(2);
+            };
+            env_logger::init();
+            runtime().block_on(client::run(strategy))
+        }
+        "winners" | "winlog" | "player" | "admin" => runtime().block_on(stats(command, args)),
+        _ => usage(),
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
+}
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Runtime::new().expect("tokio runtime should start")
+}
+
+// Флаги со значениями отдельно, позиционные аргументы отдельно
+struct Options {
+    flags: HashMap<String, String>,
+    positional: Vec<String>,
+}
+
+impl Options {
+    fn parse(args: &[String], allowed: &[&str]) -> Option<Options> {
+        let mut options = Options {
+            flags: HashMap::new(),
+            positional: Vec::new(),
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.strip_prefix("--") {
+                Some(flag)
+                    if ["format", "api-port", "key"].contains(&flag) || allowed.contains(&flag) =>
+                {
+                    options.flags.insert(flag.to_string(), args.next()?.clone());
+                }
+                Some(_) => return None,
+                None => options.positional.push(arg.clone()),
+            }
+        }
+
+        Some(options)
+    }
+
+    fn get(&self, flag: &str) -> Option<&str> {
+        self.flags.get(flag).map(String::as_str)
+    }
+
+    fn parsed<T: std::str::FromStr>(&self, flag: &str) -> Result<Option<T>, String> {
+        self.get(flag)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| format!("--{} {} is not valid", flag, value))
+            })
+            .transpose()
+    }
+}
+
+async fn stats(command: &str, args: &[String]) -> Result<(), Box<dyn Error>> {
+    let allowed: &[&str] = match command {
+        "winners" => &["room", "season"],
+        "winlog" => &["player", "room", "since", "limit"],
+        _ => &[],
+    };
+    let Some(options) = Options::parse(args, allowed) else {
+        usage();
+    };
+
+    let format = options.parsed::<Format>("format")?.unwrap_or_default();
+    let api_server_port = match options.parsed::<u32>("api-port")? {
+        Some(port) => port,
+        None => env::var("API_SERVER_PORT")
+            .map_err(|_| "--api-port or API_SERVER_PORT is required")?
+            .parse::<u32>()
+            .map_err(|_| "API_SERVER_PORT environment variable is not a valid number")?,
+    };
+    let key = options.get("key").map(str::to_string).or_else(|| {
+        env::var("API_KEY")
+            .or_else(|_| match command {
+                "admin" => env::var("ADMIN_TOKEN"),
+                _ => Err(env::VarError::NotPresent),
+            })
+            .ok()
+    });
+    let api = ApiClient::new(&format!("127.0.0.1:{}", api_server_port), key);
+
+    let table = match command {
+        "winners" => winners(&api, &options).await?,
+        "winlog" => win_log(&api, &options).await?,
+        "player" => {
+            let [player_id] = options.positional.as_slice() else {
+                usage();
+            };
+            let player_id = uuid::Uuid::parse_str(player_id)?;
+            let profile = api
+                .player(player_id)
+                .await?
+                .ok_or_else(|| format!("player {} is unknown", player_id))?;
+
+            let mut table = Table::new(vec!["player_id", "name", "avatar"]);
+            table.push(vec![
+                profile.player_id.into(),
+                profile.name.into(),
+                profile.avatar.into(),
+            ]);
+            table
+        }
+        _ => {
+            let command = match admin::parse_command(&options.positional) {
+                Ok(command) => command,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(2);
+                }
+            };
+
+            match api.admin(command).await? {
+                AdminReply::Peers(peers) => {
+                    let mut table = Table::new(vec![
+                        "player_id",
+                        "name",
+                        "signature",
+                        "address",
+                        "online",
+                        "room",
+                        "banned",
+                    ]);
+                    for peer in peers {
+                        table.push(vec![
+                            peer.player_id.into(),
+                            peer.name.into(),
+                            peer.signature.into(),
+                            peer.address.into(),
+                            peer.online.into(),
+                            peer.room.into(),
+                            peer.banned.into(),
+                        ]);
+                    }
+                    table
+                }
+                AdminReply::Done { ok: true, message } => {
+                    println!("{}", message);
+                    return Ok(());
+                }
+                AdminReply::Done { ok: false, message } => return Err(message.into()),
+            }
+        }
+    };
+
+    print!("{}", table.render(format));
+    Ok(())
+}
+
+async fn winners(api: &ApiClient, options: &Options) -> Result<Table, Box<dyn Error>> {
+    let query = match (options.get("room"), options.parsed::<u32>("season")?) {
+        (Some(_), Some(_)) => usage(),
+        (Some(room), None) => WinnersQuery::Room(room.to_string()),
+        (None, Some(season)) => WinnersQuery::Season(season),
+        (None, None) => WinnersQuery::Live,
+    };
+
+    // Колонки зависят от таблицы: подробная статистика есть только в живой таблице,
+    // а в архиве сезона нет даже онлайна
+    let mut table = Table::new(match query {
+        WinnersQuery::Live => vec![
+            "player_id",
+            "name",
+            "online",
+            "wins",
+            "score",
+            "rating",
+            "messages_received",
+            "messages_sent",
+            "early_flashes",
+            "invalid_content",
+            "rtt_ms",
+        ],
+        WinnersQuery::Room(_) => vec!["player_id", "online", "wins", "score"],
+        WinnersQuery::Season(_) => vec!["player_id", "wins", "score"],
+    });
+
+    for winner in api.winners(query.clone()).await? {
+        let mut row: Vec<Value> = vec![winner.player_id.into()];
+        match (&query, winner.stats) {
+            (WinnersQuery::Live, Some(stats)) => row.extend([
+                stats.name.into(),
+                winner.online.into(),
+                winner.wins.into(),
+                winner.score.into(),
+                stats.rating.into(),
+                stats.messages_received.into(),
+                stats.messages_sent.into(),
+                stats.early_flashes.into(),
+                stats.invalid_content.into(),
+                stats.rtt_ms.into(),
+            ]),
+            (WinnersQuery::Room(_), _) => row.extend([
+                winner.online.into(),
+                winner.wins.into(),
+                winner.score.into(),
+            ]),
+            (WinnersQuery::Season(_), _) => row.extend([winner.wins.into(), winner.score.into()]),
+            (WinnersQuery::Live, None) => continue,
+        }
+        table.push(row);
+    }
+
+    Ok(table)
+}
+
+async fn win_log(api: &ApiClient, options: &Options) -> Result<Table, Box<dyn Error>> {
+    let query = WinLogQuery {
+        player_id: options.parsed::<uuid::Uuid>("player")?,
+        room: options.get("room").map(str::to_string),
+        since: options.parsed("since")?,
+        limit: options.parsed("limit")?,
+    };
+
+    let mut table = Table::new(vec![
+        "player_id",
+        "timestamp",
+        "msg_id",
+        "latency_ms",
+        "room",
+    ]);
+    for entry in api.win_log(query).await? {
+        table.push(vec![
+            entry.player_id.into(),
+            entry.timestamp.into(),
+            entry.msg_id.into(),
+            entry.latency.as_millis().into(),
+            entry.room.into(),
+        ]);
+    }
+
+    Ok(table)
+}
@@ -0,0 +1,260 @@
+// Бот из ТЗ.
+//
+// Живет в библиотеке, чтобы его одинаково запускали бинарь client и nltt client.
+// Флаги выбирают стратегию Flash, все остальное, как и раньше, берется из env.
+
+use std::env;
+use std::error::Error;
+use std::time::Duration;
+
+use crate::game_client::{GameClient, GameEvent};
+use crate::reconnect::ReconnectConfig;
+use crate::strategy::{AdaptiveDelay, FixedDelay, FlashStrategy, RandomDelay, SkipSome};
+use crate::{generate_random_text, protocol};
+
+pub const USAGE: &str = "usage: client [--strategy random|fixed|adaptive] [--delay-ms <ms>] [--margin-ms <ms>] [--skip <probability>]";
+
+// Стратегия Flash из аргументов. --delay-ms это задержка для fixed и минимальная задержка
+// для adaptive, --margin-ms насколько adaptive старается опередить победителей.
+// --skip с любой стратегией пропускает часть сообщений
+pub fn parse_strategy(args: &[String]) -> Option<Box<dyn FlashStrategy>> {
+    let mut strategy = "random";
+    let mut delay = None;
+    let mut margin = None;
+    let mut skip = None;
+
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let value = args.next()?;
+        match flag.as_str() {
+            "--strategy" => strategy = value.as_str(),
+            "--delay-ms" => delay = Some(Duration::from_millis(value.parse().ok()?)),
+            "--margin-ms" => margin = Some(Duration::from_millis(value.parse().ok()?)),
+            "--skip" => {
+                skip = Some(
+                    value
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|probability| (0.0..=1.0).contains(probability))?,
+                )
+            }
+            _ => return None,
+        }
+    }
+
+    let strategy: Box<dyn FlashStrategy> = match strategy {
+        "random" => Box::new(RandomDelay::default()),
+        "fixed" => Box::new(FixedDelay(delay?)),
+        "adaptive" => Box::new(AdaptiveDelay::new(
+            delay.unwrap_or(Duration::from_secs(1)),
+            margin.unwrap_or(Duration::from_millis(50)),
+        )),
+        _ => return None,
+    };
+
+    Some(match skip {
+        Some(probability) => Box::new(SkipSome {
+            inner: strategy,
+            probability,
+        }),
+        None => strategy,
+    })
+}
+
+// Играет, пока не потеряет соединение насовсем. Стратегию берем из parse_strategy
+pub async fn run(mut strategy: Box<dyn FlashStrategy>) -> Result<(), Box<dyn Error>> {
+    log::info!("Flashing with the {} strategy", strategy.name());
+
+    let game_server_port = env::var("GAME_SERVER_PORT")
+        .expect("GAME_SERVER_PORT environment variable not set")
+        .parse::<u32>()
+        .expect("GAME_SERVER_PORT  environment variable is not a valid number");
+
+    let signature = if let Ok(signature) = env::var("SIGNATURE") {
+        Some(uuid::Uuid::parse_str(&signature).expect("uuid should be valid"))
+    } else {
+        None
+    };
+    // Имя, под которым нас увидят в таблицах вместо публичного id
+    let name = env::var("NAME").ok();
+    // Токен из лога прошлого запуска, чтобы продолжить оборвавшуюся сессию
+    let resume_token = env::var("RESUME_TOKEN")
+        .ok()
+        .map(|token| uuid::Uuid::parse_str(&token).expect("uuid should be valid"));
+    let server_addr = format!("127.0.0.1:{}", game_server_port);
+
+    // Переподключаемся сами, пока сервер не откажет нам в сессии или не кончатся попытки
+    let config = ReconnectConfig {
+        initial_backoff: env_duration_ms("RECONNECT_BACKOFF_MS", 500),
+        max_backoff: env_duration_ms("RECONNECT_MAX_BACKOFF_MS", 30_000),
+        max_attempts: env::var("RECONNECT_MAX_ATTEMPTS").ok().map(|attempts| {
+            attempts
+                .parse()
+                .expect("RECONNECT_MAX_ATTEMPTS is not a valid number")
+        }),
+        // Пингуем сервер сами и ждем от него хоть каких-то фреймов (сервер тоже пингует нас),
+        // иначе считаем соединение мертвым и переподключаемся
+        heartbeat_interval: env_duration_ms("HEARTBEAT_INTERVAL_MS", 5_000),
+        heartbeat_timeout: env_duration_ms("HEARTBEAT_TIMEOUT_MS", 15_000),
+        ..ReconnectConfig::default()
+    };
+    let registering = signature.is_none();
+    // Без подписи регистрируемся как новый игрок. Выданную подпись печатаем,
+    // чтобы в следующий раз прийти с ней через SIGNATURE
+    let mut client =
+        GameClient::connect(&server_addr, signature, name.clone(), resume_token, config).await?;
+    // Если комната не задана, то играем в комнате по умолчанию, куда сервер кладет всех после авторизации
+    let room = env::var("ROOM").ok();
+    let mut signature = uuid::Uuid::nil();
+
+    let mut send_content_timer = tokio::time::interval(Duration::from_secs(5));
+
+    loop {
+        let event = tokio::select! {
+            event = client.next_event() => event,
+            _ = send_content_timer.tick() => {
+                // Пока клиент переподключается, фреймы выбрасываются, так что ошибку только логируем
+                match client.send_content(generate_random_text()) {
+                    Ok(msg_id) => log::debug!("Writing regular content | msg_id: {}", msg_id),
+                    Err(e) => log::debug!("Content is not sent: {}", e),
+                }
+                continue;
+            },
+        };
+
+        match event {
+            // Клиент сдался переподключаться или сервер отказал нам в сессии
+            None => {
+                log::warn!("User {} lost the connection to the server", signature);
+                return Err("connection to the server is lost".into());
+            }
+            Some(GameEvent::Connected(session)) => {
+                signature = session.signature;
+                if registering {
+                    log::info!("Registered with the signature {}", signature);
+                }
+                log::info!(
+                    "User {} plays as {} | resume token: {}, resumed: {}",
+                    signature,
+                    session.player_id,
+                    session.resume_token,
+                    session.resumed
+                );
+
+                // Аватар, как и имя, хранится на сервере в профиле игрока
+                if let Ok(avatar) = env::var("AVATAR") {
+                    client.set_profile(name.clone(), Some(avatar))?;
+                }
+                if let Some(room) = &room {
+                    client.join_room(room)?;
+                }
+            }
+            Some(GameEvent::Disconnected { reason }) => {
+                log::warn!(
+                    "User {} is disconnected: {}, reconnecting",
+                    signature,
+                    reason
+                );
+            }
+            Some(GameEvent::Reconnected(session)) => {
+                log::info!(
+                    "User {} is back online | resumed: {}",
+                    signature,
+                    session.resumed
+                );
+                // Продолженная сессия осталась в своей комнате, а новая начинается в комнате по умолчанию
+                if let (false, Some(room)) = (session.resumed, &room) {
+                    client.join_room(room)?;
+                }
+            }
+            Some(GameEvent::ContentReceived { msg_id, body: _ }) => {
+                // Получив сообщение типа "КОНТЕНТ" от сервера, клиент должен
+                // запустить таймер на 1 секунду + random (от 250 до 500 ms). После
+                // истечения времени клиент посылает на сервер другое сообщение типа
+                // "ФЛЕШ", содержащее MSG_ID полученного сообщения.
+                // Так делает стратегия по умолчанию, остальные для экспериментов
+                match strategy.flash_delay(msg_id) {
+                    Some(delay) => client.flash_after(msg_id, delay),
+                    None => {
+                        log::debug!("Skipping the message: {}", msg_id);
+                        client.forget(msg_id);
+                    }
+                }
+            }
+            Some(GameEvent::Won { msg_id, body }) => {
+                log::info!(
+                    "User {} is a winner for the message \"{}\"| message_body is {:?}",
+                    signature,
+                    msg_id,
+                    body
+                );
+            }
+            Some(GameEvent::Lost {
+                msg_id,
+                winner,
+                winner_latency,
+            }) => {
+                strategy.observe_win_latency(Duration::from_millis(winner_latency));
+                log::info!(
+                    "User {} lost the message \"{}\" to {} ({}ms)",
+                    signature,
+                    msg_id,
+                    winner,
+                    winner_latency
+                );
+            }
+            Some(GameEvent::Error { msg_id, reason }) => {
+                log::debug!(
+                    "Server rejected our frame | msg_id: {:?}, reason: {:?}",
+                    msg_id,
+                    reason
+                );
+            }
+            Some(GameEvent::Frame(frame)) => match frame {
+                protocol::PupaFrame::Profile { name, avatar, .. } => {
+                    log::info!("User {} profile: {:?} {:?}", signature, name, avatar);
+                }
+                protocol::PupaFrame::MessageClaimed { msg_id, winner } => {
+                    log::debug!("Message \"{}\" is claimed by {}", msg_id, winner);
+                }
+                protocol::PupaFrame::SystemNotice { text } => {
+                    log::info!("Server notice: {}", text);
+                }
+                protocol::PupaFrame::Kicked { reason } => {
+                    log::warn!(
+                        "User {} is disconnected by the server: {}",
+                        signature,
+                        reason
+                    );
+                }
+                protocol::PupaFrame::RoomJoined { room } => {
+                    log::info!("User {} is playing in the room \"{}\"", signature, room);
+                }
+                protocol::PupaFrame::RoundStarted {
+                    season,
+                    remaining_ms,
+                } => {
+                    log::info!("Season {} is on, {}ms left", season, remaining_ms);
+                }
+                protocol::PupaFrame::RoundEnded { season, winner } => {
+                    log::info!("Season {} is over, winner: {:?}", season, winner);
+                }
+                _ => {
+                    /* Сервер не будет нам писать ничего кроме Pong, Profile, MessageClaimed, RoomJoined, служебных фреймов и фреймов сезона, просто игнорируем
+                     * (Ping за нас отвечает GameClient, а Unauthorized мы тут не должны получить, он больше для несанкционнированых клиентов) */
+                }
+            },
+        }
+    }
+}
+
+fn env_duration_ms(name: &str, default: u64) -> std::time::Duration {
+    let ms = env::var(name)
+        .map(|ms| {
+            ms.parse()
+                .unwrap_or_else(|_| panic!("{} is not a valid number", name))
+        })
+        .unwrap_or(default);
+
+    Duration::from_millis(ms)
+}
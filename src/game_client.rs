@@ -0,0 +1,310 @@
+// Игровой клиент для тех, кто пишет своих ботов.
+//
+// ReconnectingClient держит соединение, но отдает сырые фреймы, и таймеры Flash
+// каждому приходится заводить самому, как это раньше делал client.rs. Здесь фреймы
+// игры разобраны в типизированные события, а Flash можно отложить через flash_after:
+// клиент сам дождется таймера и не станет отправлять Flash по сообщению, которое
+// уже забрал кто-то другой или которое пропало вместе с соединением.
+
+use std::error::Error;
+use std::time::Duration;
+
+use linked_hash_map::LinkedHashMap;
+use tokio::sync::mpsc;
+
+use crate::protocol::{ErrorReason, PupaFrame};
+use crate::reconnect::{ConnectionEvent, ReconnectConfig, ReconnectingClient};
+use crate::Session;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GameEvent {
+    Connected(Session),
+    // Соединение потеряно, клиент переподключается. Отложенные Flash отменены
+    Disconnected {
+        reason: String,
+    },
+    Reconnected(Session),
+    ContentReceived {
+        msg_id: uuid::Uuid,
+        body: Vec<u8>,
+    },
+    Won {
+        msg_id: uuid::Uuid,
+        body: Vec<u8>,
+    },
+    Lost {
+        msg_id: uuid::Uuid,
+        winner: uuid::Uuid,
+        winner_latency: u64,
+    },
+    Error {
+        msg_id: Option<uuid::Uuid>,
+        reason: ErrorReason,
+    },
+    // Все остальное: комнаты, профиль, сезоны, служебные фреймы
+    Frame(PupaFrame),
+}
+
+// Больше стольких сообщений без Flash не помним, самые старые забываем. Столько же
+// хранит сервер в сторе комнаты, так что более старые все равно уже не разыграть
+const MAX_PENDING: usize = 500;
+
+pub struct GameClient {
+    connection: ReconnectingClient,
+    // Сообщения, по которым еще можно отправить Flash, в порядке получения
+    pending: LinkedHashMap<uuid::Uuid, ()>,
+    timers: mpsc::Sender<uuid::Uuid>,
+    expired_timers: mpsc::Receiver<uuid::Uuid>,
+}
+
+impl GameClient {
+    // Аргументы как у ReconnectingClient::connect: без подписи регистрируемся
+    pub async fn connect(
+        server_addr: &str,
+        signature: Option<uuid::Uuid>,
+        name: Option<String>,
+        resume_token: Option<uuid::Uuid>,
+        config: ReconnectConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        let connection =
+            ReconnectingClient::connect(server_addr, signature, name, resume_token, config).await?;
+        let (timers, expired_timers) = mpsc::channel(32);
+
+        Ok(GameClient {
+            connection,
+            pending: LinkedHashMap::new(),
+            timers,
+            expired_timers,
+        })
+    }
+
+    // None, когда соединение потеряно насовсем. Заодно отправляет Flash по истекшим таймерам,
+    // так что звать его нужно постоянно, даже если события не нужны
+    pub async fn next_event(&mut self) -> Option<GameEvent> {
+        loop {
+            let event = tokio::select! {
+                event = self.connection.next_event() => event?,
+                Some(msg_id) = self.expired_timers.recv() => {
+                    if self.pending.remove(&msg_id).is_some() {
+                        log::debug!("Sending the flash message: {}", msg_id);
+                        self.send(PupaFrame::Flash { msg_id });
+                    } else {
+                        log::debug!("Skipping the flash for already claimed message: {}", msg_id);
+                    }
+                    continue;
+                },
+            };
+
+            return Some(match event {
+                ConnectionEvent::Connected(session) => GameEvent::Connected(session),
+                ConnectionEvent::Reconnected(session) => GameEvent::Reconnected(session),
+                ConnectionEvent::Disconnected { reason } => {
+                    // Flash, отправленные без соединения, все равно пропадут
+                    self.pending.clear();
+                    GameEvent::Disconnected { reason }
+                }
+                ConnectionEvent::Frame(frame) => self.game_event(frame),
+            });
+        }
+    }
+
+    fn game_event(&mut self, frame: PupaFrame) -> GameEvent {
+        match frame {
+            PupaFrame::Content { msg_id, body } => {
+                if self.pending.len() == MAX_PENDING {
+                    self.pending.pop_front();
+                }
+                self.pending.insert(msg_id, ());
+                GameEvent::ContentReceived { msg_id, body }
+            }
+            PupaFrame::Win { msg_id, body } => {
+                self.pending.remove(&msg_id);
+                GameEvent::Won { msg_id, body }
+            }
+            PupaFrame::Lost {
+                msg_id,
+                winner,
+                winner_latency,
+            } => {
+                self.pending.remove(&msg_id);
+                GameEvent::Lost {
+                    msg_id,
+                    winner,
+                    winner_latency,
+                }
+            }
+            PupaFrame::Error { msg_id, reason } => GameEvent::Error { msg_id, reason },
+            PupaFrame::MessageClaimed { msg_id, winner } => {
+                self.pending.remove(&msg_id);
+                GameEvent::Frame(PupaFrame::MessageClaimed { msg_id, winner })
+            }
+            frame => GameEvent::Frame(frame),
+        }
+    }
+
+    // Отправляет новое сообщение в нашу комнату и возвращает его msg_id
+    pub fn send_content(&self, body: Vec<u8>) -> Result<uuid::Uuid, std::io::Error> {
+        let msg_id = uuid::Uuid::new_v4();
+        self.connection.send(PupaFrame::Content { msg_id, body })?;
+        Ok(msg_id)
+    }
+
+    // Flash прямо сейчас. Правила сервера не проверяем, слишком ранний Flash это ошибка от сервера
+    pub fn flash(&mut self, msg_id: uuid::Uuid) -> Result<(), std::io::Error> {
+        self.pending.remove(&msg_id);
+        self.connection.send(PupaFrame::Flash { msg_id })
+    }
+
+    // Не бороться за сообщение: отложенный Flash по нему не уйдет. Звать для каждого
+    // Content, на который не собираемся отвечать, чтобы он не висел в памяти
+    pub fn forget(&mut self, msg_id: uuid::Uuid) {
+        self.pending.remove(&msg_id);
+    }
+
+    // Flash через delay, если сообщение к тому времени еще никто не забрал
+    pub fn flash_after(&self, msg_id: uuid::Uuid, delay: Duration) {
+        let timers = self.timers.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let _ = timers.send(msg_id).await;
+        });
+    }
+
+    pub fn join_room(&self, room: &str) -> Result<(), std::io::Error> {
+        self.connection.send(PupaFrame::JoinRoom {
+            room: room.to_string(),
+        })
+    }
+
+    pub fn set_profile(
+        &self,
+        name: Option<String>,
+        avatar: Option<String>,
+    ) -> Result<(), std::io::Error> {
+        self.connection.send(PupaFrame::SetProfile { name, avatar })
+    }
+
+    // Любой другой фрейм, для которого нет отдельного метода
+    pub fn send_frame(&self, frame: PupaFrame) -> Result<(), std::io::Error> {
+        self.connection.send(frame)
+    }
+
+    // Закрывает соединение и останавливает переподключение. Неотправленные фреймы теряются
+    pub fn close(self) {}
+
+    // Пока клиент переподключается, фреймы выбрасываются, так что ошибку только логируем
+    fn send(&self, frame: PupaFrame) {
+        if let Err(e) = self.connection.send(frame) {
+            log::debug!("Frame is not sent: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::SinkExt;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn test_delayed_flash_is_skipped_for_claimed_message() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap().to_string();
+        let claimed = uuid::Uuid::new_v4();
+        let lost = uuid::Uuid::new_v4();
+        let free = uuid::Uuid::new_v4();
+
+        // Сервер на одно соединение: пускает, шлет три Content, одно тут же забирает,
+        // а про другое говорит, что мы его проиграли
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut stream =
+                tokio_util::codec::Framed::new(socket, crate::protocol::PupaCodec::new());
+            stream.next().await.unwrap().unwrap();
+            for frame in [
+                PupaFrame::Authorized {
+                    player_id: uuid::Uuid::new_v4(),
+                    resume_token: uuid::Uuid::new_v4(),
+                    resumed: false,
+                },
+                PupaFrame::Content {
+                    msg_id: claimed,
+                    body: vec![1],
+                },
+                PupaFrame::Content {
+                    msg_id: free,
+                    body: vec![2],
+                },
+                PupaFrame::Content {
+                    msg_id: lost,
+                    body: vec![3],
+                },
+                PupaFrame::MessageClaimed {
+                    msg_id: claimed,
+                    winner: uuid::Uuid::new_v4(),
+                },
+                PupaFrame::Lost {
+                    msg_id: lost,
+                    winner: uuid::Uuid::new_v4(),
+                    winner_latency: 1_300,
+                },
+            ] {
+                stream.send(frame).await.unwrap();
+            }
+
+            loop {
+                match stream.next().await.unwrap().unwrap() {
+                    PupaFrame::Ping { .. } => continue,
+                    frame => return frame,
+                }
+            }
+        });
+
+        let mut client = GameClient::connect(
+            &server_addr,
+            Some(uuid::Uuid::new_v4()),
+            None,
+            None,
+            ReconnectConfig::default(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            client.next_event().await,
+            Some(GameEvent::Connected(_))
+        ));
+        assert_eq!(
+            client.next_event().await,
+            Some(GameEvent::ContentReceived {
+                msg_id: claimed,
+                body: vec![1]
+            })
+        );
+        assert_eq!(
+            client.next_event().await,
+            Some(GameEvent::ContentReceived {
+                msg_id: free,
+                body: vec![2]
+            })
+        );
+        assert!(matches!(
+            client.next_event().await,
+            Some(GameEvent::ContentReceived { msg_id, .. }) if msg_id == lost
+        ));
+        assert!(matches!(
+            client.next_event().await,
+            Some(GameEvent::Frame(PupaFrame::MessageClaimed { .. }))
+        ));
+        assert!(matches!(
+            client.next_event().await,
+            Some(GameEvent::Lost { .. })
+        ));
+        // Таймеры забранного и проигранного сообщений истекают первыми, но Flash по ним не уходит
+        client.flash_after(claimed, Duration::from_millis(1));
+        client.flash_after(lost, Duration::from_millis(1));
+        client.flash_after(free, Duration::from_millis(50));
+        tokio::spawn(async move { while client.next_event().await.is_some() {} });
+
+        assert_eq!(server.await.unwrap(), PupaFrame::Flash { msg_id: free });
+    }
+}
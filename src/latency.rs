@@ -0,0 +1,94 @@
+// Статистика задержек между броадкастом Content и выигрышным Flash.
+//
+// Храним не все значения за всю историю, а только последние MAX_SAMPLES
+// на игрока, иначе долгоживущий сервер будет бесконечно копить числа.
+// Для перцентилей этого хватает, а сортировка пары тысяч u64 на запрос API дешевая.
+
+use std::collections::VecDeque;
+
+const MAX_SAMPLES: usize = 1000;
+
+#[derive(Debug, Clone, Default)]
+pub struct LatencyStats {
+    samples: VecDeque<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySummary {
+    pub count: u32,
+    pub min_ms: u64,
+    pub median_ms: u64,
+    pub p99_ms: u64,
+}
+
+impl LatencyStats {
+    pub fn new() -> Self {
+        LatencyStats::default()
+    }
+
+    pub fn record(&mut self, latency_ms: u64) {
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+
+        self.samples.push_back(latency_ms);
+    }
+
+    pub fn summary(&self) -> Option<LatencySummary> {
+        if self.samples.is_empty() {
+            return None;
+        }
+
+        let mut sorted = self.samples.iter().copied().collect::<Vec<u64>>();
+        sorted.sort_unstable();
+
+        Some(LatencySummary {
+            count: sorted.len() as u32,
+            min_ms: sorted[0],
+            median_ms: percentile(&sorted, 50),
+            p99_ms: percentile(&sorted, 99),
+        })
+    }
+}
+
+// Перцентиль по методу nearest-rank, ожидает отсортированный непустой срез
+fn percentile(sorted: &[u64], p: usize) -> u64 {
+    let rank = (p * sorted.len()).div_ceil(100);
+    sorted[rank.saturating_sub(1)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_stats_have_no_summary() {
+        assert_eq!(LatencyStats::new().summary(), None);
+    }
+
+    #[test]
+    fn test_summary_percentiles() {
+        let mut stats = LatencyStats::new();
+        for latency in (1..=100).rev() {
+            stats.record(latency);
+        }
+
+        let summary = stats.summary().unwrap();
+        assert_eq!(summary.count, 100);
+        assert_eq!(summary.min_ms, 1);
+        assert_eq!(summary.median_ms, 50);
+        assert_eq!(summary.p99_ms, 99);
+    }
+
+    #[test]
+    fn test_samples_are_bounded() {
+        let mut stats = LatencyStats::new();
+        for latency in 0..(MAX_SAMPLES as u64 + 10) {
+            stats.record(latency);
+        }
+
+        let summary = stats.summary().unwrap();
+        assert_eq!(summary.count, MAX_SAMPLES as u32);
+        assert_eq!(summary.min_ms, 10);
+    }
+}
@@ -1,13 +1,40 @@
 // Здесь собранны те структуры, которы я использовал в сервере и клиенте
 // пока их мало, я их просто определил в lib.rs
 
+pub mod access;
+pub mod admin;
+pub mod api_client;
+pub mod auth;
+pub mod client;
+pub mod game_client;
+pub mod latency;
+pub mod metrics;
+pub mod output;
+pub mod players;
 pub mod protocol;
+pub mod ratelimit;
+pub mod reconnect;
+pub mod rules;
+pub mod scoring;
+pub mod seasons;
+pub mod server;
+pub mod strategy;
+pub mod validation;
 
 use futures::SinkExt;
 use linked_hash_map::LinkedHashMap;
 use std::error::Error;
 use tokio_stream::StreamExt;
 
+// Unix время в миллисекундах, в таком виде мы храним все временные метки статистики
+pub fn now_millis() -> u128 {
+    use std::time::SystemTime;
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("smth is wrong with time :D")
+        .as_millis()
+}
+
 // Реализация, которую использует клиент. Обертка над рид-стримом
 pub struct ClientReader {
     stream: tokio_util::codec::FramedRead<tokio::net::tcp::OwnedReadHalf, protocol::PupaCodec>,
@@ -17,6 +44,21 @@ impl ClientReader {
     pub async fn read(&mut self) -> Option<Result<protocol::PupaFrame, std::io::Error>> {
         self.stream.next().await
     }
+
+    // Сервер пингует живых клиентов, так что долгая тишина значит, что соединение
+    // умерло, даже если TCP об этом еще не знает. В этом случае отдаем TimedOut
+    pub async fn read_timeout(
+        &mut self,
+        timeout: std::time::Duration,
+    ) -> Option<Result<protocol::PupaFrame, std::io::Error>> {
+        match tokio::time::timeout(timeout, self.stream.next()).await {
+            Ok(frame) => frame,
+            Err(_) => Some(Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "no frames from the server",
+            ))),
+        }
+    }
 }
 
 // Обертка над write-stream просто несколько удобных методов
@@ -41,35 +83,92 @@ impl ClientWriter {
         self.stream.send(frame).await
     }
 
-    // Подключившись к серверу клиент должен каждые 5 секунд отправлять
-    // на сервер сообщение типа "КОНТЕНТ", формата {MSG_ID, BODY}.
-    // MSG_ID должен быть уникальным (может быть UUID или другой). BODY
-    // это рандомный текст размером от 30 до 100 байт.
-    //
-    // Можно было бы добавить оверхеда и сделать std::String, но так как
-    // мы особо не заботимся о структуре контента сообщения, а просто храним какие-то
-    // байты, то пусть будет Vec<u8>. Если что, то легко поменять потом.
+    pub async fn write_ping(&mut self, nonce: u64) -> Result<(), std::io::Error> {
+        self.stream.send(protocol::PupaFrame::Ping { nonce }).await
+    }
+
+    // Ответ на Ping сервера, nonce возвращаем тот же
+    pub async fn write_pong(&mut self, nonce: u64) -> Result<(), std::io::Error> {
+        self.stream.send(protocol::PupaFrame::Pong { nonce }).await
+    }
+
+    pub async fn join_room(&mut self, room: &str) -> Result<(), std::io::Error> {
+        let frame = protocol::PupaFrame::JoinRoom {
+            room: room.to_string(),
+        };
+
+        self.stream.send(frame).await
+    }
+
+    pub async fn leave_room(&mut self) -> Result<(), std::io::Error> {
+        self.stream.send(protocol::PupaFrame::LeaveRoom).await
+    }
+
+    pub async fn list_rooms(&mut self) -> Result<(), std::io::Error> {
+        self.stream.send(protocol::PupaFrame::ListRooms).await
+    }
+
+    // Профиль задается целиком, None стирает имя или аватар
+    pub async fn set_profile(
+        &mut self,
+        name: Option<String>,
+        avatar: Option<String>,
+    ) -> Result<(), std::io::Error> {
+        self.stream
+            .send(protocol::PupaFrame::SetProfile { name, avatar })
+            .await
+    }
+
+    // Любой фрейм как есть, для всего, на что нет отдельного метода
+    pub async fn write(&mut self, frame: protocol::PupaFrame) -> Result<(), std::io::Error> {
+        self.stream.send(frame).await
+    }
+
     pub fn generate_random_text(&self) -> Vec<u8> {
-        use rand::Rng;
+        generate_random_text()
+    }
+}
 
-        let min_message_size = 30;
-        let max_message_size = 100;
-        let mut rng = rand::thread_rng();
-        let size = rng.gen_range(min_message_size..max_message_size);
+// Подключившись к серверу клиент должен каждые 5 секунд отправлять
+// на сервер сообщение типа "КОНТЕНТ", формата {MSG_ID, BODY}.
+// MSG_ID должен быть уникальным (может быть UUID или другой). BODY
+// это рандомный текст размером от 30 до 100 байт.
+//
+// Можно было бы добавить оверхеда и сделать std::String, но так как
+// мы особо не заботимся о структуре контента сообщения, а просто храним какие-то
+// байты, то пусть будет Vec<u8>. Если что, то легко поменять потом.
+pub fn generate_random_text() -> Vec<u8> {
+    use rand::Rng;
+
+    let min_message_size = 30;
+    let max_message_size = 100;
+    let mut rng = rand::thread_rng();
+    let size = rng.gen_range(min_message_size..max_message_size);
+
+    let vec: Vec<u8> = rng
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(size)
+        .collect();
+
+    vec
+}
 
-        let vec: Vec<u8> = rng
-            .sample_iter(&rand::distributions::Alphanumeric)
-            .take(size)
-            .collect();
+// Сервер ответил на Authorize или Register отказом (Banned, NonAuthorized, Error).
+// В отличие от сетевых ошибок, повторять такое подключение бессмысленно
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejected(pub protocol::PupaFrame);
 
-        vec
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "server rejected the session: {:?}", self.0)
     }
 }
 
-pub async fn connect_to_game_server(
+impl Error for Rejected {}
+
+async fn open_game_connection(
     server_addr: &str,
-    signature: Option<uuid::Uuid>,
-) -> Result<(ClientReader, ClientWriter, uuid::Uuid), Box<dyn Error>> {
+) -> Result<(ClientReader, ClientWriter), Box<dyn Error>> {
     log::debug!("Connecting to {} ...", server_addr);
 
     let stream = tokio::net::TcpStream::connect(server_addr).await?;
@@ -83,28 +182,226 @@ pub async fn connect_to_game_server(
         stream: tokio_util::codec::FramedRead::new(read_half, codec.clone()),
     };
 
-    let mut client_writer = ClientWriter {
+    let client_writer = ClientWriter {
         stream: tokio_util::codec::FramedWrite::new(write_half, codec),
     };
 
-    let signature = signature.unwrap_or_else(|| uuid::Uuid::new_v4());
+    Ok((client_reader, client_writer))
+}
+
+// Что сервер сказал нам при входе. Подпись это наш пароль, player_id видят другие игроки,
+// а с resume_token можно переподключиться и продолжить эту же сессию
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Session {
+    pub signature: uuid::Uuid,
+    pub player_id: uuid::Uuid,
+    pub resume_token: uuid::Uuid,
+    // true, если сервер продолжил прошлую сессию и сейчас дошлет пропущенные фреймы
+    pub resumed: bool,
+}
+
+// Подключение с подписью. Если передать resume_token прошлой сессии и успеть в окно
+// переподключения сервера, то игрок остается в своей комнате, а все, что пришло
+// ему за время отсутствия (Content, Win, Lost), сервер дошлет сразу после Authorized
+pub async fn connect_to_game_server(
+    server_addr: &str,
+    signature: Option<uuid::Uuid>,
+    name: Option<String>,
+    resume_token: Option<uuid::Uuid>,
+) -> Result<(ClientReader, ClientWriter, Session), Box<dyn Error>> {
+    let (mut client_reader, mut client_writer) = open_game_connection(server_addr).await?;
+
+    let signature = signature.unwrap_or_else(uuid::Uuid::new_v4);
 
     log::debug!("Authorizing with key provided {}", signature);
 
-    let frame = protocol::PupaFrame::Authorize { signature };
+    let frame = protocol::PupaFrame::Authorize {
+        signature,
+        name,
+        resume_token,
+    };
     client_writer.stream.send(frame).await?;
 
-    Ok((client_reader, client_writer, signature))
+    match client_reader.read().await {
+        Some(Ok(protocol::PupaFrame::Authorized {
+            player_id,
+            resume_token,
+            resumed,
+        })) => Ok((
+            client_reader,
+            client_writer,
+            Session {
+                signature,
+                player_id,
+                resume_token,
+                resumed,
+            },
+        )),
+        Some(Ok(frame)) => Err(Rejected(frame).into()),
+        Some(Err(e)) => Err(e.into()),
+        None => Err("server closed the connection on authorization".into()),
+    }
+}
+
+// Регистрация нового игрока. Сервер выдает подпись, ее нужно сохранить и дальше
+// подключаться с ней через connect_to_game_server. Соединение после регистрации
+// уже авторизовано, можно сразу играть
+pub async fn register_on_game_server(
+    server_addr: &str,
+    name: Option<String>,
+) -> Result<(ClientReader, ClientWriter, Session), Box<dyn Error>> {
+    let (mut client_reader, mut client_writer) = open_game_connection(server_addr).await?;
+
+    log::debug!("Registering a new player");
+    client_writer
+        .stream
+        .send(protocol::PupaFrame::Register { name })
+        .await?;
+
+    match client_reader.read().await {
+        Some(Ok(protocol::PupaFrame::Registered {
+            signature,
+            player_id,
+            resume_token,
+        })) => Ok((
+            client_reader,
+            client_writer,
+            Session {
+                signature,
+                player_id,
+                resume_token,
+                resumed: false,
+            },
+        )),
+        Some(Ok(frame)) => Err(Rejected(frame).into()),
+        Some(Err(e)) => Err(e.into()),
+        None => Err("server closed the connection on registration".into()),
+    }
+}
+
+// Сообщение, которое ждет своего победителя. Кроме тела запоминаем момент
+// броадкаста, чтобы потом посчитать, как быстро до нас долетел выигрышный Flash,
+// автора и тех, кому сообщение действительно было доставлено. Бороться за сообщение
+// могут только получатели, иначе автор (или тот, кто угадал id) выигрывал бы всегда.
+// Для правил, где победитель выбирается после закрытия окна, тут же копятся попытки.
+pub struct StoredMessage {
+    pub msg_id: uuid::Uuid,
+    pub author: uuid::Uuid,
+    pub body: Vec<u8>,
+    pub broadcast_at: std::time::Instant,
+    pub recipients: std::collections::HashSet<uuid::Uuid>,
+    pub attempts: Vec<rules::FlashAttempt>,
+}
+
+impl StoredMessage {
+    pub fn new(
+        msg_id: uuid::Uuid,
+        author: uuid::Uuid,
+        body: Vec<u8>,
+        broadcast_at: std::time::Instant,
+        recipients: std::collections::HashSet<uuid::Uuid>,
+    ) -> Self {
+        StoredMessage {
+            msg_id,
+            author,
+            body,
+            broadcast_at,
+            recipients,
+            attempts: Vec::new(),
+        }
+    }
+}
+
+// Чем закончился Flash с точки зрения MessageStore
+pub enum FlashOutcome {
+    // Такого сообщения нет и никогда не было (или о нем уже забыли)
+    Unknown,
+    // Сообщение забрано, победитель известен
+    Won(StoredMessage, rules::FlashAttempt),
+    // Сообщение уже забрал кто-то другой
+    Lost(rules::FlashAttempt),
+    // Попытка записана, победитель определится в resolve_at.
+    // first_attempt говорит, что это первая попытка и resolve еще никто не запланировал
+    Pending {
+        resolve_at: std::time::Instant,
+        first_attempt: bool,
+    },
+}
+
+// Почему сообщение ушло из стора
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetireReason {
+    // Разыграно, у него есть победитель
+    Claimed(rules::FlashAttempt),
+    // Вытеснено более новыми сообщениями, когда стор заполнился
+    Evicted,
+    // Пролежало дольше ttl
+    Expired,
+    // Окно закрылось, но правила никого не выбрали
+    Unclaimed,
 }
 
+impl RetireReason {
+    fn outcome(&self) -> Result<FlashOutcome, protocol::ErrorReason> {
+        match self {
+            RetireReason::Claimed(winner) => Ok(FlashOutcome::Lost(*winner)),
+            RetireReason::Evicted => Err(protocol::ErrorReason::Evicted),
+            RetireReason::Expired => Err(protocol::ErrorReason::Expired),
+            RetireReason::Unclaimed => Err(protocol::ErrorReason::TooLate),
+        }
+    }
+}
+
+#[derive(Default)]
 pub struct MessageStore {
-    messages: linked_hash_map::LinkedHashMap<uuid::Uuid, Vec<u8>>,
+    messages: linked_hash_map::LinkedHashMap<uuid::Uuid, StoredMessage>,
+    // Сколько живет сообщение после броадкаста. None - пока его не вытеснят новые
+    ttl: Option<std::time::Duration>,
+    // Недавно ушедшие из стора сообщения, чтобы на опоздавший Flash ответить
+    // понятным фреймом, а не молчанием, и чтобы msg_id нельзя было использовать повторно.
+    // Это всего лишь uuid и причина, так что помним гораздо больше, чем сами сообщения
+    retired: linked_hash_map::LinkedHashMap<uuid::Uuid, RetireReason>,
 }
 
 impl MessageStore {
     pub fn new() -> Self {
+        MessageStore::with_ttl(None)
+    }
+
+    pub fn with_ttl(ttl: Option<std::time::Duration>) -> Self {
         MessageStore {
             messages: LinkedHashMap::new(),
+            ttl,
+            retired: LinkedHashMap::new(),
+        }
+    }
+
+    fn retire(&mut self, msg_id: uuid::Uuid, reason: RetireReason) {
+        if self.retired.len() == 10_000 {
+            self.retired.pop_front();
+        }
+        self.retired.insert(msg_id, reason);
+    }
+
+    // Выкидываем из начала очереди все, что старше ttl. Сообщения лежат в порядке броадкаста,
+    // так что как только нашли свежее, дальше можно не смотреть. Отдельного таймера нет,
+    // чистим при каждом обращении к стору, этого достаточно, чтобы протухшее нельзя было выиграть.
+    //
+    // Сообщения, у которых уже есть попытки, не трогаем: их заберет resolve, когда закроется окно.
+    fn expire(&mut self, now: std::time::Instant) {
+        let Some(ttl) = self.ttl else {
+            return;
+        };
+
+        while let Some((&msg_id, message)) = self.messages.front() {
+            if !message.attempts.is_empty()
+                || now.saturating_duration_since(message.broadcast_at) < ttl
+            {
+                break;
+            }
+
+            self.messages.pop_front();
+            self.retire(msg_id, RetireReason::Expired);
         }
     }
 
@@ -114,27 +411,119 @@ impl MessageStore {
     // любому из клиентов захочется записать новый Content, еще придется брать lock()
     // когда мы будет искать победителя. Операций мало, lock хотя бы будет коротким.
     //
-    // Еще один момент, мы считаем, что uuid всегда уникальные (это касается и ключей пользователя и msg_id)
-    pub fn insert(&mut self, msg_id: uuid::Uuid, value: Vec<u8>) {
+    // Еще один момент, мы считаем, что uuid всегда уникальные (это касается и ключей пользователя и msg_id).
+    // Случайная коллизия маловероятна, а вот клиент может прислать старый msg_id нарочно,
+    // чтобы разыграть сообщение заново, поэтому повторы отклоняем, а не затираем.
+    pub fn insert(&mut self, message: StoredMessage) -> Result<(), protocol::ErrorReason> {
+        self.expire(message.broadcast_at);
+
+        if self.is_known(message.msg_id) {
+            return Err(protocol::ErrorReason::DuplicateMessage);
+        }
+
         if self.messages.len() == 500 {
-            self.messages.pop_front();
+            if let Some((msg_id, _)) = self.messages.pop_front() {
+                self.retire(msg_id, RetireReason::Evicted);
+            }
         }
 
-        // Если вдруг так получится, что у нас коллизия uuid, то мы просто затираем старое сообщение и даже не скажем об этом клиенту (но какова вероятность?)
-        self.messages.insert(msg_id, value);
+        self.messages.insert(message.msg_id, message);
+        Ok(())
+    }
+
+    // Сообщение с таким msg_id сейчас разыгрывается или недавно ушло из стора
+    pub fn is_known(&self, msg_id: uuid::Uuid) -> bool {
+        self.messages.contains_key(&msg_id) || self.retired.contains_key(&msg_id)
+    }
+
+    // Err - сообщение есть, но правила не разрешают этому игроку на него претендовать,
+    // или его уже нет, потому что оно протухло или было вытеснено.
+    // Отклоненный Flash не забирает сообщение, за него можно побороться еще раз.
+    pub fn flash(
+        &mut self,
+        msg_id: uuid::Uuid,
+        player_id: uuid::Uuid,
+        flashed_at: std::time::Instant,
+        rules: &dyn rules::GameRules,
+    ) -> Result<FlashOutcome, protocol::ErrorReason> {
+        self.expire(flashed_at);
+
+        let Some(message) = self.messages.get_mut(&msg_id) else {
+            return match self.retired.get(&msg_id) {
+                Some(reason) => reason.outcome(),
+                None => Ok(FlashOutcome::Unknown),
+            };
+        };
+
+        let attempt = rules::FlashAttempt {
+            player_id,
+            latency: flashed_at.saturating_duration_since(message.broadcast_at),
+        };
+        rules.check_flash(message, &attempt)?;
+
+        match rules.resolution() {
+            rules::Resolution::Immediate => match rules.select_winner(&[attempt]) {
+                Some(_) => {
+                    self.retire(msg_id, RetireReason::Claimed(attempt));
+                    Ok(self
+                        .messages
+                        .remove(&msg_id)
+                        .map(|message| FlashOutcome::Won(message, attempt))
+                        .unwrap_or(FlashOutcome::Unknown))
+                }
+                None => Err(protocol::ErrorReason::NotSelected),
+            },
+            rules::Resolution::AfterWindow(window) => {
+                message.attempts.push(attempt);
+                Ok(FlashOutcome::Pending {
+                    resolve_at: message.broadcast_at + window,
+                    first_attempt: message.attempts.len() == 1,
+                })
+            }
+        }
+    }
+
+    // Закрываем окно для сообщения: забираем его из стора и выбираем победителя среди попыток.
+    // Сообщение уходит из стора, даже если победителя нет, и его msg_id остается занятым
+    pub fn resolve(
+        &mut self,
+        msg_id: uuid::Uuid,
+        rules: &dyn rules::GameRules,
+    ) -> Option<(StoredMessage, rules::FlashAttempt)> {
+        let message = self.messages.remove(&msg_id)?;
+        let Some(winner) = rules.select_winner(&message.attempts) else {
+            self.retire(msg_id, RetireReason::Unclaimed);
+            return None;
+        };
+        let attempt = message.attempts[winner];
+        self.retire(msg_id, RetireReason::Claimed(attempt));
+
+        Some((message, attempt))
     }
 
-    pub fn extract(&mut self, msg_id: uuid::Uuid) -> Option<(uuid::Uuid, Vec<u8>)> {
-        self.messages.remove(&msg_id).map(|bytes| (msg_id, bytes))
+    // Все msg_id, о которых помнит стор: и те, что разыгрываются, и недавно ушедшие
+    pub fn known_ids(&self) -> impl Iterator<Item = uuid::Uuid> + '_ {
+        self.messages.keys().chain(self.retired.keys()).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
     }
 }
 
 struct WinLog {
-    signature: uuid::Uuid,
+    player_id: uuid::Uuid,
     timestamp: u128,
     msg_id: uuid::Uuid,
+    latency_ms: u64,
+    room: String,
 }
 
+#[derive(Default)]
 pub struct WinLogStore {
     records: std::collections::VecDeque<WinLog>,
 }
@@ -146,14 +535,16 @@ impl WinLogStore {
         }
     }
 
-    // Дата и время, Токен пользователя, MSG_ID
-    pub fn insert(&mut self, msg_id: uuid::Uuid, signature: uuid::Uuid) {
-        use std::time::SystemTime;
-        let now = SystemTime::now();
-        let timestamp = now
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .expect("smth is wrong with time :D")
-            .as_millis();
+    // Дата и время, публичный id игрока, MSG_ID, задержка от броадкаста до выигрышного Flash
+    // и комната, в которой было разыграно сообщение
+    pub fn insert(
+        &mut self,
+        msg_id: uuid::Uuid,
+        player_id: uuid::Uuid,
+        latency_ms: u64,
+        room: String,
+    ) {
+        let timestamp = now_millis();
 
         if self.records.len() == 100 {
             self.records.pop_front();
@@ -163,14 +554,267 @@ impl WinLogStore {
         self.records.push_back(WinLog {
             msg_id,
             timestamp,
-            signature,
+            player_id,
+            latency_ms,
+            room,
         });
     }
 
-    pub fn get_all(&self) -> Vec<(uuid::Uuid, u128, uuid::Uuid)> {
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    pub fn get_all(&self) -> Vec<(uuid::Uuid, u128, uuid::Uuid, u64, String)> {
         self.records
             .iter()
-            .map(|win_log| (win_log.signature, win_log.timestamp, win_log.msg_id))
+            .map(|win_log| {
+                (
+                    win_log.player_id,
+                    win_log.timestamp,
+                    win_log.msg_id,
+                    win_log.latency_ms,
+                    win_log.room.clone(),
+                )
+            })
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn first_flash_wins() -> rules::FirstFlashWins {
+        rules::FirstFlashWins {
+            min_flash_delay: Duration::from_millis(1000),
+        }
+    }
+
+    #[test]
+    fn test_flash_only_by_recipients() {
+        let author = uuid::Uuid::new_v4();
+        let recipient = uuid::Uuid::new_v4();
+        let stranger = uuid::Uuid::new_v4();
+        let msg_id = uuid::Uuid::new_v4();
+        let broadcast_at = Instant::now();
+        let flashed_at = broadcast_at + Duration::from_millis(1300);
+        let rules = first_flash_wins();
+
+        let mut store = MessageStore::new();
+        store
+            .insert(StoredMessage::new(
+                msg_id,
+                author,
+                vec![1, 2, 3],
+                broadcast_at,
+                [recipient].into_iter().collect(),
+            ))
+            .unwrap();
+
+        assert_eq!(
+            store.flash(msg_id, author, flashed_at, &rules).err(),
+            Some(protocol::ErrorReason::OwnMessage)
+        );
+        assert_eq!(
+            store.flash(msg_id, stranger, flashed_at, &rules).err(),
+            Some(protocol::ErrorReason::NotRecipient)
+        );
+        assert_eq!(store.len(), 1);
+
+        match store.flash(msg_id, recipient, flashed_at, &rules) {
+            Ok(FlashOutcome::Won(message, attempt)) => {
+                assert_eq!(message.body, vec![1, 2, 3]);
+                assert_eq!(attempt.player_id, recipient);
+            }
+            _ => panic!("recipient should win the message"),
+        }
+        assert!(matches!(
+            store.flash(msg_id, recipient, flashed_at, &rules),
+            Ok(FlashOutcome::Lost(winner)) if winner.player_id == recipient
+        ));
+    }
+
+    #[test]
+    fn test_flash_rejects_early_flash() {
+        let recipient = uuid::Uuid::new_v4();
+        let msg_id = uuid::Uuid::new_v4();
+        let broadcast_at = Instant::now();
+        let rules = first_flash_wins();
+
+        let mut store = MessageStore::new();
+        store
+            .insert(StoredMessage::new(
+                msg_id,
+                uuid::Uuid::new_v4(),
+                vec![],
+                broadcast_at,
+                [recipient].into_iter().collect(),
+            ))
+            .unwrap();
+
+        let early = broadcast_at + Duration::from_millis(300);
+        assert_eq!(
+            store.flash(msg_id, recipient, early, &rules).err(),
+            Some(protocol::ErrorReason::TooEarly)
+        );
+
+        let in_time = broadcast_at + Duration::from_millis(1300);
+        assert!(matches!(
+            store.flash(msg_id, recipient, in_time, &rules),
+            Ok(FlashOutcome::Won(_, _))
+        ));
+    }
+
+    #[test]
+    fn test_deferred_resolution_picks_winner_among_attempts() {
+        let first = uuid::Uuid::new_v4();
+        let second = uuid::Uuid::new_v4();
+        let msg_id = uuid::Uuid::new_v4();
+        let broadcast_at = Instant::now();
+        let rules = rules::ClosestToTargetWins {
+            min_flash_delay: Duration::from_millis(1000),
+            target_delay: Duration::from_millis(1375),
+            window: Duration::from_millis(2000),
+        };
+
+        let mut store = MessageStore::new();
+        store
+            .insert(StoredMessage::new(
+                msg_id,
+                uuid::Uuid::new_v4(),
+                vec![],
+                broadcast_at,
+                [first, second].into_iter().collect(),
+            ))
+            .unwrap();
+
+        let outcome = store.flash(
+            msg_id,
+            first,
+            broadcast_at + Duration::from_millis(1100),
+            &rules,
+        );
+        assert!(matches!(
+            outcome,
+            Ok(FlashOutcome::Pending {
+                first_attempt: true,
+                ..
+            })
+        ));
+        let outcome = store.flash(
+            msg_id,
+            second,
+            broadcast_at + Duration::from_millis(1400),
+            &rules,
+        );
+        assert!(matches!(
+            outcome,
+            Ok(FlashOutcome::Pending {
+                first_attempt: false,
+                ..
+            })
+        ));
+
+        let (_, winner) = store.resolve(msg_id, &rules).unwrap();
+        assert_eq!(winner.player_id, second);
+        assert!(store.is_empty());
+
+        // Без попыток победителя нет, но msg_id все равно занят, а на Flash отвечаем TooLate
+        let unclaimed = uuid::Uuid::new_v4();
+        store
+            .insert(StoredMessage::new(
+                unclaimed,
+                uuid::Uuid::new_v4(),
+                vec![],
+                broadcast_at,
+                [first].into_iter().collect(),
+            ))
+            .unwrap();
+        assert!(store.resolve(unclaimed, &rules).is_none());
+        assert!(store.is_known(unclaimed));
+        assert_eq!(
+            store
+                .flash(
+                    unclaimed,
+                    first,
+                    broadcast_at + Duration::from_millis(2500),
+                    &rules
+                )
+                .err(),
+            Some(protocol::ErrorReason::TooLate)
+        );
+    }
+
+    #[test]
+    fn test_flash_on_expired_message() {
+        let recipient = uuid::Uuid::new_v4();
+        let stale = uuid::Uuid::new_v4();
+        let fresh = uuid::Uuid::new_v4();
+        let broadcast_at = Instant::now();
+        let rules = first_flash_wins();
+
+        let mut store = MessageStore::with_ttl(Some(Duration::from_secs(5)));
+        for (msg_id, at) in [
+            (stale, broadcast_at),
+            (fresh, broadcast_at + Duration::from_secs(3)),
+        ] {
+            store
+                .insert(StoredMessage::new(
+                    msg_id,
+                    uuid::Uuid::new_v4(),
+                    vec![],
+                    at,
+                    [recipient].into_iter().collect(),
+                ))
+                .unwrap();
+        }
+
+        let flashed_at = broadcast_at + Duration::from_secs(6);
+        assert_eq!(
+            store.flash(stale, recipient, flashed_at, &rules).err(),
+            Some(protocol::ErrorReason::Expired)
+        );
+        assert_eq!(store.len(), 1);
+        assert!(matches!(
+            store.flash(fresh, recipient, flashed_at, &rules),
+            Ok(FlashOutcome::Won(_, _))
+        ));
+    }
+
+    #[test]
+    fn test_insert_rejects_reused_msg_id() {
+        let recipient = uuid::Uuid::new_v4();
+        let msg_id = uuid::Uuid::new_v4();
+        let broadcast_at = Instant::now();
+        let rules = first_flash_wins();
+        let message = || {
+            StoredMessage::new(
+                msg_id,
+                uuid::Uuid::new_v4(),
+                vec![],
+                broadcast_at,
+                [recipient].into_iter().collect(),
+            )
+        };
+
+        let mut store = MessageStore::new();
+        assert_eq!(store.insert(message()), Ok(()));
+        assert_eq!(
+            store.insert(message()),
+            Err(protocol::ErrorReason::DuplicateMessage)
+        );
+
+        // И после победы сообщение нельзя разыграть заново
+        let flashed_at = broadcast_at + Duration::from_millis(1300);
+        assert!(matches!(
+            store.flash(msg_id, recipient, flashed_at, &rules),
+            Ok(FlashOutcome::Won(_, _))
+        ));
+        assert!(store.is_empty());
+        assert_eq!(
+            store.insert(message()),
+            Err(protocol::ErrorReason::DuplicateMessage)
+        );
+    }
+}
@@ -0,0 +1,349 @@
+// Метрики игрового сервера в формате Prometheus.
+//
+// Можно было бы взять крейт prometheus, но нам нужно всего несколько счетчиков
+// и один текстовый формат отдачи, поэтому реестр и HTTP-ручка написаны руками
+// поверх атомиков и tokio. Все счетчики лежат в одной структуре, которую сервер
+// держит в Arc и раздает хэндлерам, так что брать lock() ради метрик не нужно.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+// Монотонный счетчик
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Значение, которое может как расти, так и уменьшаться
+#[derive(Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Общие мьютексы сервера, время ожидания которых мы хотим видеть
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lock {
+    State,
+    MessageStore,
+    WinLogStore,
+}
+
+impl Lock {
+    const ALL: [Lock; 3] = [Lock::State, Lock::MessageStore, Lock::WinLogStore];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Lock::State => "state",
+            Lock::MessageStore => "message_store",
+            Lock::WinLogStore => "winlog_store",
+        }
+    }
+}
+
+#[derive(Default)]
+struct LockWait {
+    acquisitions: Counter,
+    // Храним микросекунды, чтобы не возиться с float в атомиках,
+    // в секунды переводим только при отдаче метрик
+    wait_micros: Counter,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    pub connected_peers: Gauge,
+    pub content_received: Counter,
+    pub broadcasts_delivered: Counter,
+    pub broadcasts_dropped: Counter,
+    pub flashes_received: Counter,
+    pub flashes_rejected: Counter,
+    pub wins: Counter,
+    pub decode_errors: Counter,
+    pub rate_limited: Counter,
+    pub heartbeat_timeouts: Counter,
+    pub message_store_size: Gauge,
+    lock_wait: [LockWait; 3],
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    // Обертка над lock(), которая заодно меряет, сколько мы простояли в очереди за мьютексом
+    pub async fn lock<'a, T>(
+        &self,
+        lock: Lock,
+        mutex: &'a tokio::sync::Mutex<T>,
+    ) -> tokio::sync::MutexGuard<'a, T> {
+        let started_at = std::time::Instant::now();
+        let guard = mutex.lock().await;
+
+        let lock_wait = &self.lock_wait[lock as usize];
+        lock_wait.acquisitions.inc();
+        lock_wait
+            .wait_micros
+            .add(started_at.elapsed().as_micros() as u64);
+
+        guard
+    }
+
+    // Текстовый формат Prometheus (version 0.0.4)
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        write_metric(
+            &mut out,
+            "nltt_connected_peers",
+            "gauge",
+            "Number of authorized game sessions",
+            self.connected_peers.get(),
+        );
+        write_metric(
+            &mut out,
+            "nltt_content_received_total",
+            "counter",
+            "Content frames received from clients",
+            self.content_received.get(),
+        );
+        write_metric(
+            &mut out,
+            "nltt_broadcasts_delivered_total",
+            "counter",
+            "Content frames handed to peer channels",
+            self.broadcasts_delivered.get(),
+        );
+        write_metric(
+            &mut out,
+            "nltt_broadcasts_dropped_total",
+            "counter",
+            "Content frames that could not be handed to a peer channel",
+            self.broadcasts_dropped.get(),
+        );
+        write_metric(
+            &mut out,
+            "nltt_flashes_received_total",
+            "counter",
+            "Flash frames received from clients",
+            self.flashes_received.get(),
+        );
+        write_metric(
+            &mut out,
+            "nltt_flashes_rejected_total",
+            "counter",
+            "Flash frames rejected with an error frame",
+            self.flashes_rejected.get(),
+        );
+        write_metric(
+            &mut out,
+            "nltt_wins_total",
+            "counter",
+            "Messages won by players",
+            self.wins.get(),
+        );
+        write_metric(
+            &mut out,
+            "nltt_decode_errors_total",
+            "counter",
+            "Frames that failed to decode",
+            self.decode_errors.get(),
+        );
+        write_metric(
+            &mut out,
+            "nltt_rate_limited_total",
+            "counter",
+            "Content and Flash frames rejected by rate limits",
+            self.rate_limited.get(),
+        );
+        write_metric(
+            &mut out,
+            "nltt_heartbeat_timeouts_total",
+            "counter",
+            "Game sessions closed because the peer stopped responding",
+            self.heartbeat_timeouts.get(),
+        );
+        write_metric(
+            &mut out,
+            "nltt_message_store_size",
+            "gauge",
+            "Messages currently held in MessageStore",
+            self.message_store_size.get(),
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP nltt_lock_wait_seconds_total Time spent waiting for shared locks"
+        );
+        let _ = writeln!(out, "# TYPE nltt_lock_wait_seconds_total counter");
+        for lock in Lock::ALL {
+            let wait = &self.lock_wait[lock as usize];
+            let _ = writeln!(
+                out,
+                "nltt_lock_wait_seconds_total{{lock=\"{}\"}} {}",
+                lock.name(),
+                wait.wait_micros.get() as f64 / 1_000_000.0
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP nltt_lock_acquisitions_total Number of times shared locks were taken"
+        );
+        let _ = writeln!(out, "# TYPE nltt_lock_acquisitions_total counter");
+        for lock in Lock::ALL {
+            let wait = &self.lock_wait[lock as usize];
+            let _ = writeln!(
+                out,
+                "nltt_lock_acquisitions_total{{lock=\"{}\"}} {}",
+                lock.name(),
+                wait.acquisitions.get()
+            );
+        }
+
+        out
+    }
+}
+
+fn write_metric(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    value: impl std::fmt::Display,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+// Минимальный HTTP сервер, который умеет отдавать только GET /metrics.
+// Тащить hyper ради одной ручки не хочется, а Prometheus большего и не просит.
+pub async fn serve(listener: tokio::net::TcpListener, metrics: std::sync::Arc<Metrics>) {
+    loop {
+        let (socket, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                log::error!(
+                    "Metrics server | failed to accept connection; error = {:?}",
+                    e
+                );
+                continue;
+            }
+        };
+
+        let metrics = std::sync::Arc::clone(&metrics);
+        tokio::spawn(async move {
+            if let Err(e) = handle_http(socket, &metrics).await {
+                log::debug!(
+                    "Metrics server | connection [{}:{}] failed; error = {:?}",
+                    peer.ip(),
+                    peer.port(),
+                    e
+                );
+            }
+        });
+    }
+}
+
+async fn handle_http(
+    mut socket: tokio::net::TcpStream,
+    metrics: &Metrics,
+) -> Result<(), std::io::Error> {
+    // Заголовки нам не интересны, читаем до конца запроса только чтобы
+    // разобрать первую строку. Ограничиваем размер, чтобы нам не прислали гигабайт.
+    let mut request = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = socket.read(&mut chunk).await?;
+        if read == 0 || request.len() > 8 * 1024 {
+            return Ok(());
+        }
+        request.extend_from_slice(&chunk[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+
+    let response = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = metrics.render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_prometheus_text() {
+        let metrics = Metrics::new();
+        metrics.connected_peers.inc();
+        metrics.connected_peers.inc();
+        metrics.connected_peers.dec();
+        metrics.wins.add(3);
+
+        let rendered = metrics.render();
+
+        assert!(rendered.contains("# TYPE nltt_connected_peers gauge\nnltt_connected_peers 1\n"));
+        assert!(rendered.contains("nltt_wins_total 3\n"));
+        assert!(rendered.contains("nltt_lock_wait_seconds_total{lock=\"message_store\"} 0\n"));
+    }
+
+    #[tokio::test]
+    async fn test_lock_records_acquisitions() {
+        let metrics = Metrics::new();
+        let mutex = tokio::sync::Mutex::new(0);
+
+        *metrics.lock(Lock::WinLogStore, &mutex).await += 1;
+
+        assert_eq!(
+            metrics.lock_wait[Lock::WinLogStore as usize]
+                .acquisitions
+                .get(),
+            1
+        );
+        assert_eq!(
+            metrics.lock_wait[Lock::State as usize].acquisitions.get(),
+            0
+        );
+    }
+}
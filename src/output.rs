@@ -0,0 +1,281 @@
+// Вывод статистики для людей и для скриптов.
+//
+// Команды статистики собирают ответ в Table, а печатают его в выбранном формате:
+// выровненной таблицей для глаз, CSV или JSON для того, чтобы отдать в другие
+// инструменты. JSON пишем сами, ради массива плоских объектов тащить serde_json
+// не хочется. Отсутствующее значение в таблице это "-", в CSV пустая ячейка, в JSON null.
+
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    #[default]
+    Table,
+    Json,
+    Csv,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "table" => Ok(Format::Table),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!(
+                "unknown format {}, expected table, json or csv",
+                format
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(u128),
+    Text(String),
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(value: u32) -> Self {
+        Value::Number(value.into())
+    }
+}
+
+impl From<u64> for Value {
+    fn from(value: u64) -> Self {
+        Value::Number(value.into())
+    }
+}
+
+impl From<u128> for Value {
+    fn from(value: u128) -> Self {
+        Value::Number(value)
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::Text(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Text(value.to_string())
+    }
+}
+
+impl From<uuid::Uuid> for Value {
+    fn from(value: uuid::Uuid) -> Self {
+        Value::Text(value.to_string())
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+pub struct Table {
+    columns: Vec<&'static str>,
+    rows: Vec<Vec<Value>>,
+}
+
+impl Table {
+    pub fn new(columns: Vec<&'static str>) -> Self {
+        Table {
+            columns,
+            rows: Vec::new(),
+        }
+    }
+
+    // В строке столько же значений, сколько колонок, и в том же порядке
+    pub fn push(&mut self, row: Vec<Value>) {
+        assert_eq!(row.len(), self.columns.len(), "row does not match columns");
+        self.rows.push(row);
+    }
+
+    pub fn render(&self, format: Format) -> String {
+        match format {
+            Format::Table => self.render_table(),
+            Format::Json => self.render_json(),
+            Format::Csv => self.render_csv(),
+        }
+    }
+
+    fn render_table(&self) -> String {
+        let cells = self
+            .rows
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|value| match value {
+                        Value::Null => "-".to_string(),
+                        Value::Bool(value) => value.to_string(),
+                        Value::Number(value) => value.to_string(),
+                        Value::Text(value) => value.clone(),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let widths = self
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(index, column)| {
+                cells
+                    .iter()
+                    .map(|row| row[index].chars().count())
+                    .chain(std::iter::once(column.len()))
+                    .max()
+                    .unwrap_or_default()
+            })
+            .collect::<Vec<_>>();
+
+        let mut output = String::new();
+        let header = self.columns.iter().map(|column| column.to_string());
+        for row in std::iter::once(header.collect::<Vec<_>>()).chain(cells) {
+            let line = row
+                .iter()
+                .zip(widths.iter())
+                .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                .collect::<Vec<_>>()
+                .join("  ");
+            let _ = writeln!(output, "{}", line.trim_end());
+        }
+
+        output
+    }
+
+    fn render_csv(&self) -> String {
+        let mut output = self.columns.join(",");
+        output.push('\n');
+
+        for row in self.rows.iter() {
+            let line = row
+                .iter()
+                .map(|value| match value {
+                    Value::Null => String::new(),
+                    Value::Bool(value) => value.to_string(),
+                    Value::Number(value) => value.to_string(),
+                    Value::Text(value) if value.contains([',', '"', '\n', '\r']) => {
+                        format!("\"{}\"", value.replace('"', "\"\""))
+                    }
+                    Value::Text(value) => value.clone(),
+                })
+                .collect::<Vec<_>>()
+                .join(",");
+            output.push_str(&line);
+            output.push('\n');
+        }
+
+        output
+    }
+
+    // Массив объектов, по одному на строку, чтобы вывод было удобно и читать, и грепать
+    fn render_json(&self) -> String {
+        if self.rows.is_empty() {
+            return "[]\n".to_string();
+        }
+
+        let objects = self
+            .rows
+            .iter()
+            .map(|row| {
+                let fields = self
+                    .columns
+                    .iter()
+                    .zip(row.iter())
+                    .map(|(column, value)| {
+                        let value = match value {
+                            Value::Null => "null".to_string(),
+                            Value::Bool(value) => value.to_string(),
+                            Value::Number(value) => value.to_string(),
+                            Value::Text(value) => json_string(value),
+                        };
+                        format!("{}:{}", json_string(column), value)
+                    })
+                    .collect::<Vec<_>>();
+                format!("  {{{}}}", fields.join(","))
+            })
+            .collect::<Vec<_>>();
+
+        format!("[\n{}\n]\n", objects.join(",\n"))
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Table {
+        let mut table = Table::new(vec!["player", "name", "wins", "online"]);
+        table.push(vec![
+            "a".into(),
+            Some("Pupa, \"the\" best").into(),
+            12u32.into(),
+            true.into(),
+        ]);
+        table.push(vec![
+            "b".into(),
+            None::<String>.into(),
+            3u32.into(),
+            false.into(),
+        ]);
+        table
+    }
+
+    #[test]
+    fn test_render_formats() {
+        assert_eq!(
+            sample().render(Format::Table),
+            "player  name              wins  online\n\
+             a       Pupa, \"the\" best  12    true\n\
+             b       -                 3     false\n"
+        );
+        assert_eq!(
+            sample().render(Format::Csv),
+            "player,name,wins,online\n\
+             a,\"Pupa, \"\"the\"\" best\",12,true\n\
+             b,,3,false\n"
+        );
+        assert_eq!(
+            sample().render(Format::Json),
+            "[\n  {\"player\":\"a\",\"name\":\"Pupa, \\\"the\\\" best\",\"wins\":12,\"online\":true},\n  \
+             {\"player\":\"b\",\"name\":null,\"wins\":3,\"online\":false}\n]\n"
+        );
+        assert_eq!(Table::new(vec!["player"]).render(Format::Json), "[]\n");
+    }
+}
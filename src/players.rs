@@ -0,0 +1,224 @@
+// Публичные id игроков.
+//
+// Подпись это пароль игрока, поэтому наружу она не уходит, а выдаем ее обладателю
+// постоянный публичный id. Раньше соответствие жило только в памяти, и после перезапуска
+// сервера у всех игроков менялись id. Теперь его можно хранить в файле, по строке на игрока:
+//
+//   96a9354f-a8bc-4895-8317-61bf73f127c8 0b6f3f4e-2b3a-4c5e-9a51-3f1d2e7c8a90
+//
+// Сначала подпись, потом публичный id. Если у игрока есть профиль, за ними через табы
+// идут имя и аватар, пустое поле значит, что его нет. Табов в имени и аватаре не бывает,
+// управляющие символы protocol::is_valid_display_name и is_valid_avatar не пропускают.
+//
+// Файл только дописывается: новый игрок сразу при первом входе, новый профиль при каждом
+// SetProfile, так что он переживает и падение сервера. Из нескольких строк одной подписи
+// действует последняя.
+
+use std::collections::HashMap;
+use std::io::Write;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    pub name: Option<String>,
+    pub avatar: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct PlayerRegistry {
+    players: HashMap<uuid::Uuid, (uuid::Uuid, Profile)>,
+    // Без файла игроки живут, пока живет сервер
+    file: Option<std::fs::File>,
+}
+
+impl PlayerRegistry {
+    pub fn new() -> Self {
+        PlayerRegistry::default()
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut registry = PlayerRegistry::new();
+
+        for (number, line) in text.lines().enumerate() {
+            // Аватар может кончаться пробелом, так что целиком строку не тримим
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let mut fields = line.trim_end_matches('\r').split('\t');
+            let ids = fields.next().unwrap_or_default().trim();
+            let mut field = || {
+                fields
+                    .next()
+                    .filter(|field| !field.is_empty())
+                    .map(String::from)
+            };
+            let profile = Profile {
+                name: field(),
+                avatar: field(),
+            };
+
+            let parsed = ids
+                .split_once(char::is_whitespace)
+                .and_then(|(signature, player_id)| {
+                    Some((
+                        uuid::Uuid::parse_str(signature).ok()?,
+                        uuid::Uuid::parse_str(player_id.trim()).ok()?,
+                    ))
+                });
+            let Some((signature, player_id)) = parsed else {
+                return Err(format!(
+                    "line {}: expected a signature and a player id in {}",
+                    number + 1,
+                    line
+                ));
+            };
+            registry.players.insert(signature, (player_id, profile));
+        }
+
+        Ok(registry)
+    }
+
+    // Файла еще нет - начинаем с пустого, он появится с первым игроком
+    pub fn open(path: &str) -> Result<Self, String> {
+        let mut registry = match std::fs::read_to_string(path) {
+            Ok(text) => PlayerRegistry::parse(&text).map_err(|e| format!("{}: {}", path, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => PlayerRegistry::new(),
+            Err(e) => return Err(format!("cannot read {}: {}", path, e)),
+        };
+        registry.file = Some(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("cannot open {}: {}", path, e))?,
+        );
+
+        Ok(registry)
+    }
+
+    pub fn get(&self, signature: uuid::Uuid) -> Option<uuid::Uuid> {
+        self.players
+            .get(&signature)
+            .map(|(player_id, _)| *player_id)
+    }
+
+    pub fn profile(&self, signature: uuid::Uuid) -> Option<&Profile> {
+        self.players.get(&signature).map(|(_, profile)| profile)
+    }
+
+    // Профиль по публичному id, для API. Запрос редкий, так что просто ищем перебором
+    pub fn profile_of(&self, player_id: uuid::Uuid) -> Option<&Profile> {
+        self.players
+            .values()
+            .find(|(id, _)| *id == player_id)
+            .map(|(_, profile)| profile)
+    }
+
+    // Вход игрока: публичный id и профиль. При первом входе придумываем новый id.
+    // Имя из Authorize, если оно есть, заменяет сохраненное. Изменения сразу пишем в файл
+    pub fn admit(&mut self, signature: uuid::Uuid, name: Option<String>) -> (uuid::Uuid, Profile) {
+        let (player_id, profile) = match self.players.get(&signature) {
+            Some((player_id, profile)) => (*player_id, profile.clone()),
+            None => (uuid::Uuid::new_v4(), Profile::default()),
+        };
+        let admitted = Profile {
+            name: name.or(profile.name.clone()),
+            ..profile.clone()
+        };
+
+        if !self.players.contains_key(&signature) || admitted != profile {
+            self.players
+                .insert(signature, (player_id, admitted.clone()));
+            self.save(signature);
+        }
+
+        (player_id, admitted)
+    }
+
+    // Профиль меняется целиком, как и в SetProfile. Незнакомую подпись не заводим
+    pub fn set_profile(&mut self, signature: uuid::Uuid, profile: Profile) {
+        match self.players.get_mut(&signature) {
+            Some((_, current)) if *current != profile => *current = profile,
+            _ => return,
+        }
+        self.save(signature);
+    }
+
+    // Дописываем актуальную строку игрока. Если записать не вышло, игрок все равно играет,
+    // но после перезапуска получит новый id или старый профиль
+    fn save(&mut self, signature: uuid::Uuid) {
+        let (Some(file), Some((player_id, profile))) =
+            (&mut self.file, self.players.get(&signature))
+        else {
+            return;
+        };
+        let written = if *profile == Profile::default() {
+            writeln!(file, "{} {}", signature, player_id)
+        } else {
+            writeln!(
+                file,
+                "{} {}\t{}\t{}",
+                signature,
+                player_id,
+                profile.name.as_deref().unwrap_or_default(),
+                profile.avatar.as_deref().unwrap_or_default()
+            )
+        };
+        if let Err(e) = written {
+            log::error!("Player {} is not saved: {}", player_id, e);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.players.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.players.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_players_survive_reopen() {
+        let path = std::env::temp_dir().join(format!("nltt-players-{}", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+        let signature = uuid::Uuid::new_v4();
+
+        let mut registry = PlayerRegistry::open(path).unwrap();
+        assert_eq!(registry.get(signature), None);
+        let (player_id, _) = registry.admit(signature, Some("Pupa".to_string()));
+        assert_eq!(registry.admit(signature, None).0, player_id);
+        let profile = Profile {
+            name: Some("Pupa Lupa".to_string()),
+            avatar: Some("(^_^) ".to_string()),
+        };
+        registry.set_profile(signature, profile.clone());
+        assert_eq!(
+            registry.admit(signature, None),
+            (player_id, profile.clone())
+        );
+        drop(registry);
+
+        let mut registry = PlayerRegistry::open(path).unwrap();
+        assert_eq!(registry.get(signature), Some(player_id));
+        assert_eq!(registry.profile(signature), Some(&profile));
+        assert_eq!(registry.len(), 1);
+
+        // Стертое имя тоже переживает перезапуск
+        let renamed = Profile {
+            name: None,
+            ..profile
+        };
+        registry.set_profile(signature, renamed.clone());
+        drop(registry);
+        let registry = PlayerRegistry::open(path).unwrap();
+        assert_eq!(registry.profile_of(player_id), Some(&renamed));
+        std::fs::remove_file(path).unwrap();
+
+        assert!(PlayerRegistry::parse("not-a-uuid also-not").is_err());
+    }
+}
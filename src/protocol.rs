@@ -3,6 +3,9 @@ use std::io;
 use serde::{Deserialize, Serialize};
 use tokio_util::codec::{Decoder, Encoder};
 
+// Комната, в которую попадает игрок сразу после авторизации и куда возвращается по LeaveRoom
+pub const DEFAULT_ROOM: &str = "main";
+
 // Фрейм нашего протокола. Несмотря на то, что мы используем
 // TCP, где данные передаются просто, как стрим байтов, мы
 // можем выделить логические блоки, которые называются фреймами.
@@ -10,9 +13,16 @@ use tokio_util::codec::{Decoder, Encoder};
 // клиент и сервер.
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub enum PupaFrame {
-    // Фрейм для авторизации
+    // Фрейм для авторизации. Подпись остается секретом игрока, в таблицах и рассылках
+    // его видно только по публичному id, который сервер выдает в ответ (Authorized),
+    // и по имени, если игрок его задал
+    //
+    // resume_token из прошлого Authorized позволяет продолжить сессию после обрыва,
+    // если игрок успел переподключиться в окно RESUME_GRACE_MS сервера
     Authorize {
         signature: uuid::Uuid,
+        name: Option<String>,
+        resume_token: Option<uuid::Uuid>,
     },
     NonAuthorized,
     Content {
@@ -28,18 +38,281 @@ pub enum PupaFrame {
     },
     ShowWinners,
     WinnerRecord {
-        signature: uuid::Uuid,
+        player_id: uuid::Uuid,
+        name: Option<String>,
         online: bool,
         wins: u32,
+        score: u64,
+        // Рейтинг Эло, если он включен на сервере, иначе у всех начальный
+        rating: u32,
         messages_received: u32,
         messages_sent: u32,
+        early_flashes: u32,
+        invalid_content: u32,
+        // Медиана времени Ping/Pong с сервером, если игрок уже отвечал на Ping
+        rtt_ms: Option<u64>,
     },
     ShowWinnersLog,
     WinLogRecord {
-        signature: uuid::Uuid,
+        player_id: uuid::Uuid,
         timestamp: u128,
         msg_id: uuid::Uuid,
+        latency_ms: u64,
+        room: String,
+    },
+    // Статистика задержек выигрышных Flash по игрокам
+    ShowLatencies,
+    LatencyRecord {
+        player_id: uuid::Uuid,
+        samples: u32,
+        min_ms: u64,
+        median_ms: u64,
+        p99_ms: u64,
+    },
+    // Сервер отказался принять фрейм клиента. msg_id заполнен, если ошибка касается
+    // конкретного сообщения (Content или Flash)
+    Error {
+        msg_id: Option<uuid::Uuid>,
+        reason: ErrorReason,
+    },
+    // Комнаты. Content и победы живут внутри комнаты, в которой сейчас находится игрок
+    JoinRoom {
+        room: String,
+    },
+    LeaveRoom,
+    RoomJoined {
+        room: String,
+    },
+    ListRooms,
+    Rooms {
+        rooms: Vec<RoomInfo>,
+    },
+    // Таблица победителей внутри одной комнаты (API)
+    ShowRoomWinners {
+        room: String,
+    },
+    RoomWinnerRecord {
+        room: String,
+        player_id: uuid::Uuid,
+        online: bool,
+        wins: u32,
+        score: u64,
+    },
+    // Сезоны. Сервер сообщает игрокам о начале и конце сезона,
+    // в конце сезона живая статистика сбрасывается и уходит в архив
+    RoundStarted {
+        season: u32,
+        remaining_ms: u64,
+    },
+    RoundEnded {
+        season: u32,
+        winner: Option<uuid::Uuid>,
+    },
+    // Архив сезонов (API)
+    ShowSeasons,
+    SeasonRecord {
+        season: u32,
+        started_at: u128,
+        ended_at: u128,
+        players: u32,
+    },
+    ShowSeasonWinners {
+        season: u32,
+    },
+    SeasonWinnerRecord {
+        season: u32,
+        player_id: uuid::Uuid,
+        wins: u32,
+        score: u64,
+    },
+    // Сообщение уже забрал другой игрок. Приходит в ответ на Flash по выигранному сообщению,
+    // а при отложенном выборе победителя всем проигравшим претендентам
+    Lost {
+        msg_id: uuid::Uuid,
+        winner: uuid::Uuid,
+        winner_latency: u64,
+    },
+    // Рассылается всей комнате, когда у сообщения появился победитель (если включено на сервере)
+    MessageClaimed {
+        msg_id: uuid::Uuid,
+        winner: uuid::Uuid,
     },
+    // Первый фрейм на API порту, ключ из API_KEYS_FILE (или ADMIN_TOKEN) сервера.
+    // Без него доступно только то, что сервер разрешает без ключей
+    ApiAuthorize {
+        key: String,
+    },
+    // Администрирование (API), нужен ключ с правом admin
+    Admin {
+        command: AdminCommand,
+    },
+    AdminResult {
+        ok: bool,
+        message: String,
+    },
+    // Подпись видна только здесь, админке она нужна для списков доступа
+    PeerRecord {
+        player_id: uuid::Uuid,
+        name: Option<String>,
+        signature: uuid::Uuid,
+        address: String,
+        online: bool,
+        room: String,
+        banned: bool,
+    },
+    // Объявление от администратора всем игрокам
+    SystemNotice {
+        text: String,
+    },
+    // Администратор отключил игрока, после этого фрейма сервер закрывает соединение
+    Kicked {
+        reason: String,
+    },
+    // Отказ в Authorize из-за бана или списка доступа, в отличие от NonAuthorized с причиной
+    Banned {
+        reason: AccessDenied,
+    },
+    // Ответ на успешный Authorize: публичный id игрока, постоянный для его подписи,
+    // и токен для продолжения этой сессии. resumed говорит, что продолжилась прошлая
+    // сессия, и следом придут фреймы, пропущенные за время обрыва
+    Authorized {
+        player_id: uuid::Uuid,
+        resume_token: uuid::Uuid,
+        resumed: bool,
+    },
+    // Регистрация нового игрока вместо Authorize. Сервер сам выдает подпись,
+    // дальше с ней нужно приходить в Authorize. Соединение сразу авторизовано
+    Register {
+        name: Option<String>,
+    },
+    Registered {
+        signature: uuid::Uuid,
+        player_id: uuid::Uuid,
+        resume_token: uuid::Uuid,
+    },
+    // Профиль игрока задается целиком, None стирает поле. Сервер отвечает Profile
+    SetProfile {
+        name: Option<String>,
+        avatar: Option<String>,
+    },
+    // Профиль игрока по публичному id (API)
+    ShowProfile {
+        player_id: uuid::Uuid,
+    },
+    Profile {
+        player_id: uuid::Uuid,
+        name: Option<String>,
+        avatar: Option<String>,
+    },
+    // Проверка, что соединение живо. Пинговать может любая сторона, в ответ
+    // сразу приходит Pong с тем же nonce, по нему и считается время туда и обратно
+    Ping {
+        nonce: u64,
+    },
+    Pong {
+        nonce: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum AccessDenied {
+    // Подпись забанена списком доступа, админом или за ранние Flash
+    SignatureBanned,
+    // Адрес попадает в забаненную сеть
+    AddressBanned,
+    // Список доступа разрешает вход только перечисленным подписям или сетям
+    NotAllowed,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub enum AdminCommand {
+    // Все игроки, которых сервер видел с момента запуска, отвечаем PeerRecord на каждого
+    ListPeers,
+    // Игроки указываются публичным id из PeerRecord
+    Kick { player_id: uuid::Uuid },
+    // Бан до перезапуска сервера, онлайн игрока заодно отключаем
+    Ban { player_id: uuid::Uuid },
+    Unban { player_id: uuid::Uuid },
+    Notice { text: String },
+    // Обнулить всю живую статистику и лог побед. Архив сезонов не трогаем
+    ResetStats,
+    // Поменять лимит на лету. Имена и формат значений те же, что у переменных окружения
+    // сервера (например CONTENT_RATE_PER_SEC), None снимает лимит
+    SetLimit { name: String, value: Option<String> },
+    // Перечитать файл со списками доступа
+    ReloadAccessList,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct RoomInfo {
+    pub name: String,
+    pub players: u32,
+}
+
+// Имя комнаты: от 1 до 32 символов, латиница, цифры, '-' и '_'
+pub fn is_valid_room_name(room: &str) -> bool {
+    !room.is_empty()
+        && room.len() <= 32
+        && room
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// Имя игрока: от 1 до 32 символов без управляющих и без пробелов по краям
+pub fn is_valid_display_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().count() <= 32
+        && name.trim() == name
+        && !name.chars().any(char::is_control)
+}
+
+// Аватар это короткий текст вроде эмодзи или пары букв: до 8 символов без управляющих
+pub fn is_valid_avatar(avatar: &str) -> bool {
+    !avatar.trim().is_empty()
+        && avatar.chars().count() <= 8
+        && !avatar.chars().any(char::is_control)
+}
+
+// Причины, по которым сервер может отклонить фрейм клиента
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ErrorReason {
+    // Flash на собственное сообщение
+    OwnMessage,
+    // Flash на сообщение, которое игроку не доставлялось
+    NotRecipient,
+    // Flash пришел раньше минимального времени реакции после броадкаста
+    TooEarly,
+    // Flash пришел, когда окно приема попыток для сообщения уже закрылось
+    TooLate,
+    // Игрок уже сделал попытку для этого сообщения, а правила разрешают только одну
+    AlreadyFlashed,
+    // Правила игры не признали этот Flash победным
+    NotSelected,
+    // Имя комнаты не прошло проверку is_valid_room_name
+    InvalidRoomName,
+    // Сообщение пролежало дольше MESSAGE_TTL_MS и больше не разыгрывается
+    Expired,
+    // Сообщение вытеснено более новыми, когда в сторе комнаты кончилось место
+    Evicted,
+    // Игрок присылает Content или Flash чаще, чем разрешено
+    RateLimited,
+    // Тело Content не прошло проверку ContentPolicy на сервере
+    BodyTooShort,
+    BodyTooLong,
+    InvalidBodyCharset,
+    InvalidBodyEncoding,
+    // Сообщение с таким msg_id уже было, каждое сообщение разыгрывается только один раз
+    DuplicateMessage,
+    // У API ключа нет прав на этот запрос
+    Forbidden,
+    // Имя в Authorize, Register или SetProfile не прошло проверку is_valid_display_name
+    InvalidDisplayName,
+    // Аватар в SetProfile не прошел проверку is_valid_avatar
+    InvalidAvatar,
+    // Игрока с таким публичным id сервер не знает
+    UnknownPlayer,
+    // На сервере уже MAX_ROOMS комнат, новую не создать, пока какая-нибудь не опустеет
+    TooManyRooms,
 }
 
 // Кодек позволяет нам превратить наш фрейм в байты и обратно.
@@ -58,6 +331,12 @@ impl PupaCodec {
     }
 }
 
+impl Default for PupaCodec {
+    fn default() -> Self {
+        PupaCodec::new()
+    }
+}
+
 impl Encoder<PupaFrame> for PupaCodec {
     type Error = io::Error;
 
@@ -83,10 +362,7 @@ impl Decoder for PupaCodec {
                         self.already_waited = false;
                         Ok(Some(decoded))
                     }
-                    Err(_) => Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        "Failed to calculate serialized size",
-                    )),
+                    Err(_) => Err(io::Error::other("Failed to calculate serialized size")),
                 },
                 Err(_err) => {
                     // Протокол продуман не до конца, поэтому осталась одна проблема,
@@ -98,10 +374,7 @@ impl Decoder for PupaCodec {
                     if self.already_waited {
                         self.already_waited = false;
                         buf.clear();
-                        Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            "Failed to decode Frame, cleaning buffer",
-                        ))
+                        Err(io::Error::other("Failed to decode Frame, cleaning buffer"))
                     } else {
                         self.already_waited = true;
                         Ok(None)
@@ -160,4 +433,27 @@ mod tests {
         assert_eq!(frame2, decoded2);
         assert_eq!(buffer.len(), 0);
     }
+
+    #[test]
+    fn test_room_name_validation() {
+        assert!(is_valid_room_name(DEFAULT_ROOM));
+        assert!(is_valid_room_name("speed-run_2"));
+        assert!(!is_valid_room_name(""));
+        assert!(!is_valid_room_name("with space"));
+        assert!(!is_valid_room_name(&"x".repeat(33)));
+    }
+
+    #[test]
+    fn test_display_name_validation() {
+        assert!(is_valid_display_name("Пупа и Лупа"));
+        assert!(is_valid_display_name(&"я".repeat(32)));
+        assert!(!is_valid_display_name(""));
+        assert!(!is_valid_display_name(" padded"));
+        assert!(!is_valid_display_name("line\nbreak"));
+        assert!(!is_valid_display_name(&"я".repeat(33)));
+
+        assert!(is_valid_avatar("(^_^)"));
+        assert!(!is_valid_avatar("   "));
+        assert!(!is_valid_avatar("123456789"));
+    }
 }
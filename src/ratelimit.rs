@@ -0,0 +1,88 @@
+// Ограничение частоты фреймов от игрока.
+//
+// Классический token bucket: в ведре помещается burst токенов, каждый фрейм забирает
+// один, а токены доливаются с постоянной скоростью. Так игрок может изредка прислать
+// пачку фреймов подряд, но в среднем не быстрее заданной частоты.
+
+use std::time::Instant;
+
+// Настройка лимита, читается сервером из переменных окружения
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub per_sec: f64,
+    pub burst: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    // Новое ведро сразу полное
+    pub fn new(limit: RateLimit, now: Instant) -> Self {
+        TokenBucket {
+            limit,
+            tokens: limit.burst,
+            updated_at: now,
+        }
+    }
+
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_sec).min(self.limit.burst);
+        self.updated_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    // Ведро успело долиться доверху, то есть им давно не пользовались
+    pub fn is_full(&self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens + elapsed * self.limit.per_sec >= self.limit.burst
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_bucket_allows_burst_then_refills() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(
+            RateLimit {
+                per_sec: 2.0,
+                burst: 3.0,
+            },
+            now,
+        );
+
+        assert!(bucket.try_take(now));
+        assert!(bucket.try_take(now));
+        assert!(bucket.try_take(now));
+        assert!(!bucket.try_take(now));
+
+        // За полсекунды доливается ровно один токен
+        let later = now + Duration::from_millis(500);
+        assert!(bucket.try_take(later));
+        assert!(!bucket.try_take(later));
+
+        // Больше burst не накапливается, сколько ни жди
+        let much_later = later + Duration::from_secs(60);
+        assert!(!bucket.is_full(later));
+        assert!(bucket.is_full(much_later));
+        for _ in 0..3 {
+            assert!(bucket.try_take(much_later));
+        }
+        assert!(!bucket.try_take(much_later));
+    }
+}
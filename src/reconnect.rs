@@ -0,0 +1,393 @@
+// Клиент, который сам переподключается к игровому серверу.
+//
+// Обычный клиент на connect_to_game_server живет ровно одно соединение: сервер
+// перезапустился или сеть моргнула, и все, игра окончена. Здесь соединением владеет
+// фоновая задача. Когда оно рвется, она ждет по экспоненте с джиттером (чтобы толпа
+// ботов после рестарта сервера не ломилась в него одновременно) и входит заново с той же
+// подписью и resume_token прошлой сессии. Приложение видит все это как поток событий.
+//
+// Ping сервера задача отвечает сама, а если сервер замолчал дольше heartbeat_timeout,
+// то считает соединение мертвым, даже если TCP этого еще не заметил. Столько же ждем
+// ответа на авторизацию при подключении, а не ответивший сервер это неудачная попытка.
+
+use std::error::Error;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+
+use crate::protocol::PupaFrame;
+use crate::{connect_to_game_server, register_on_game_server, ClientReader, ClientWriter};
+use crate::{Rejected, Session};
+
+#[derive(Debug, Clone)]
+pub struct ReconnectConfig {
+    // Пауза перед первой попыткой, дальше она умножается на multiplier, но не больше max_backoff
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: f64,
+    // Доля паузы, на которую она случайно укорачивается, от 0 до 1
+    pub jitter: f64,
+    // Сколько попыток подряд делаем, прежде чем сдаться. None - пробуем бесконечно
+    pub max_attempts: Option<u32>,
+    pub heartbeat_interval: Duration,
+    pub heartbeat_timeout: Duration,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: None,
+            heartbeat_interval: Duration::from_secs(5),
+            heartbeat_timeout: Duration::from_secs(15),
+        }
+    }
+}
+
+impl ReconnectConfig {
+    // Пауза перед попыткой номер attempt (с нуля). random это случайное число из [0, 1)
+    pub fn backoff(&self, attempt: u32, random: f64) -> Duration {
+        let backoff = self.initial_backoff.as_secs_f64() * self.multiplier.powi(attempt as i32);
+        let backoff = backoff.min(self.max_backoff.as_secs_f64());
+        Duration::from_secs_f64(backoff * (1.0 - self.jitter * random))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    // Первое подключение, всегда приходит первым событием
+    Connected(Session),
+    // Соединение потеряно, клиент уже пытается подключиться снова.
+    // Все, что приложение отправит до Reconnected, будет выброшено
+    Disconnected { reason: String },
+    // Снова в игре. Если сессия не продолжилась (resumed: false), то мы в комнате по умолчанию
+    Reconnected(Session),
+    // Фрейм от сервера, кроме Ping, на него клиент отвечает сам
+    Frame(PupaFrame),
+}
+
+pub struct ReconnectingClient {
+    outgoing: mpsc::Sender<PupaFrame>,
+    events: mpsc::Receiver<ConnectionEvent>,
+}
+
+impl ReconnectingClient {
+    // Первое подключение делаем сразу, и если оно не удалось, отдаем ошибку, а не
+    // переподключаемся: скорее всего неверный адрес или подпись. Без подписи регистрируемся,
+    // и дальше переподключаемся с выданной
+    pub async fn connect(
+        server_addr: &str,
+        signature: Option<uuid::Uuid>,
+        name: Option<String>,
+        resume_token: Option<uuid::Uuid>,
+        config: ReconnectConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        // Сервер, который принял соединение и молчит, не должен вешать нас навсегда
+        let connected = match signature {
+            Some(signature) => {
+                tokio::time::timeout(
+                    config.heartbeat_timeout,
+                    connect_to_game_server(
+                        server_addr,
+                        Some(signature),
+                        name.clone(),
+                        resume_token,
+                    ),
+                )
+                .await
+            }
+            None => {
+                tokio::time::timeout(
+                    config.heartbeat_timeout,
+                    register_on_game_server(server_addr, name.clone()),
+                )
+                .await
+            }
+        };
+        let (reader, writer, session) = connected.map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "server did not answer the authorization",
+            )
+        })??;
+
+        let (outgoing, outgoing_rx) = mpsc::channel(32);
+        let (events_tx, events) = mpsc::channel(32);
+        let _ = events_tx.send(ConnectionEvent::Connected(session)).await;
+
+        tokio::spawn(run_connection(
+            server_addr.to_string(),
+            name,
+            config,
+            session,
+            reader,
+            writer,
+            outgoing_rx,
+            events_tx,
+        ));
+
+        Ok(ReconnectingClient { outgoing, events })
+    }
+
+    // Фрейм на сервер. Не ждет ни отправки, ни переподключения: если очередь полна
+    // или клиент сдался, то фрейм выбрасывается с ошибкой
+    pub fn send(&self, frame: PupaFrame) -> Result<(), std::io::Error> {
+        self.outgoing.try_send(frame).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => {
+                std::io::Error::new(std::io::ErrorKind::WouldBlock, "outgoing queue is full")
+            }
+            mpsc::error::TrySendError::Closed(_) => std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "client gave up reconnecting",
+            ),
+        })
+    }
+
+    // None, когда клиент сдался переподключаться или сервер отказал в сессии
+    pub async fn next_event(&mut self) -> Option<ConnectionEvent> {
+        self.events.recv().await
+    }
+}
+
+// Чем закончилась одна сессия
+enum SessionEnd {
+    Lost(String),
+    // Приложение бросило клиента, переподключаться больше не для кого
+    Closed,
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_connection(
+    server_addr: String,
+    name: Option<String>,
+    config: ReconnectConfig,
+    mut session: Session,
+    mut reader: ClientReader,
+    mut writer: ClientWriter,
+    mut outgoing: mpsc::Receiver<PupaFrame>,
+    events: mpsc::Sender<ConnectionEvent>,
+) {
+    loop {
+        let reason =
+            match run_session(&config, &mut reader, &mut writer, &mut outgoing, &events).await {
+                SessionEnd::Lost(reason) => reason,
+                SessionEnd::Closed => return,
+            };
+        log::warn!("Connection to {} is lost: {}", server_addr, reason);
+        if events
+            .send(ConnectionEvent::Disconnected { reason })
+            .await
+            .is_err()
+        {
+            return;
+        }
+
+        let mut attempt = 0;
+        let reconnected = loop {
+            if config.max_attempts.is_some_and(|max| attempt >= max) {
+                log::warn!("Giving up reconnecting to {}", server_addr);
+                return;
+            }
+
+            let backoff = config.backoff(attempt, rand::random());
+            log::debug!("Reconnecting to {} in {:?}", server_addr, backoff);
+            if !discard_outgoing_for(backoff, &mut outgoing).await {
+                return;
+            }
+
+            // Попытка, на которую сервер не ответил за heartbeat_timeout, тоже неудачная
+            let connected = tokio::time::timeout(
+                config.heartbeat_timeout,
+                connect_to_game_server(
+                    &server_addr,
+                    Some(session.signature),
+                    name.clone(),
+                    Some(session.resume_token),
+                ),
+            )
+            .await;
+            match connected {
+                Err(_) => {
+                    log::debug!("Reconnect attempt {} timed out", attempt + 1);
+                    attempt += 1;
+                }
+                Ok(Ok(connection)) => break connection,
+                // Забанили или подпись больше не принимают, повторять бессмысленно
+                Ok(Err(e)) if e.downcast_ref::<Rejected>().is_some() => {
+                    log::warn!("Reconnect to {} is rejected: {}", server_addr, e);
+                    return;
+                }
+                Ok(Err(e)) => {
+                    log::debug!("Reconnect attempt {} failed: {}", attempt + 1, e);
+                    attempt += 1;
+                }
+            }
+        };
+
+        (reader, writer, session) = reconnected;
+        log::info!(
+            "Reconnected to {} | resumed: {}",
+            server_addr,
+            session.resumed
+        );
+        if events
+            .send(ConnectionEvent::Reconnected(session))
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+async fn run_session(
+    config: &ReconnectConfig,
+    reader: &mut ClientReader,
+    writer: &mut ClientWriter,
+    outgoing: &mut mpsc::Receiver<PupaFrame>,
+    events: &mpsc::Sender<ConnectionEvent>,
+) -> SessionEnd {
+    let started = std::time::Instant::now();
+    let mut heartbeat = tokio::time::interval(config.heartbeat_interval);
+    // Срок тишины отсчитываем от последнего фрейма сервера. Таймаут на каждое чтение не
+    // годится: select пересоздает его на каждом нашем Ping и исходящем фрейме, и он не истекает
+    let silence = tokio::time::sleep(config.heartbeat_timeout);
+    tokio::pin!(silence);
+
+    loop {
+        tokio::select! {
+            _ = &mut silence => {
+                return SessionEnd::Lost("no frames from the server".to_string());
+            }
+            frame = reader.read() => {
+                silence
+                    .as_mut()
+                    .reset(tokio::time::Instant::now() + config.heartbeat_timeout);
+                match frame {
+                Some(Ok(PupaFrame::Ping { nonce })) => {
+                    if let Err(e) = writer.write_pong(nonce).await {
+                        return SessionEnd::Lost(e.to_string());
+                    }
+                }
+                Some(Ok(PupaFrame::Pong { nonce })) => {
+                    let rtt = started.elapsed().saturating_sub(Duration::from_micros(nonce));
+                    log::debug!("Server round trip: {}ms", rtt.as_millis());
+                }
+                Some(Ok(frame)) => {
+                    if events.send(ConnectionEvent::Frame(frame)).await.is_err() {
+                        return SessionEnd::Closed;
+                    }
+                }
+                Some(Err(e)) => return SessionEnd::Lost(e.to_string()),
+                None => return SessionEnd::Lost("server closed the connection".to_string()),
+                }
+            },
+            frame = outgoing.recv() => match frame {
+                Some(frame) => {
+                    if let Err(e) = writer.write(frame).await {
+                        return SessionEnd::Lost(e.to_string());
+                    }
+                }
+                None => return SessionEnd::Closed,
+            },
+            _ = heartbeat.tick() => {
+                if let Err(e) = writer.write_ping(started.elapsed().as_micros() as u64).await {
+                    return SessionEnd::Lost(e.to_string());
+                }
+            }
+        }
+    }
+}
+
+// Ждем паузу перед переподключением. Все, что приложение отправляет в это время,
+// выбрасываем: Content и Flash через полминуты уже никому не нужны.
+// false, если приложение бросило клиента
+async fn discard_outgoing_for(pause: Duration, outgoing: &mut mpsc::Receiver<PupaFrame>) -> bool {
+    let sleep = tokio::time::sleep(pause);
+    tokio::pin!(sleep);
+
+    loop {
+        tokio::select! {
+            _ = &mut sleep => return true,
+            frame = outgoing.recv() => match frame {
+                Some(frame) => log::debug!("Not connected, dropping {:?}", frame),
+                None => return false,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_until_max_with_jitter() {
+        let config = ReconnectConfig {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(1000),
+            multiplier: 2.0,
+            jitter: 0.5,
+            ..ReconnectConfig::default()
+        };
+
+        assert_eq!(config.backoff(0, 0.0), Duration::from_millis(100));
+        assert_eq!(config.backoff(3, 0.0), Duration::from_millis(800));
+        assert_eq!(config.backoff(4, 0.0), Duration::from_millis(1000));
+        assert_eq!(config.backoff(40, 0.0), Duration::from_millis(1000));
+        // Джиттер только укорачивает паузу, и не больше чем на свою долю
+        assert_eq!(config.backoff(1, 0.5), Duration::from_millis(150));
+        assert!(config.backoff(4, 0.999) > Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn test_silent_server_is_lost() {
+        use futures::{SinkExt, StreamExt};
+
+        // Сервер пускает нас и замолкает, но соединение не закрывает
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut framed =
+                tokio_util::codec::Framed::new(stream, crate::protocol::PupaCodec::new());
+            let _ = framed.next().await;
+            let authorized = PupaFrame::Authorized {
+                player_id: uuid::Uuid::new_v4(),
+                resume_token: uuid::Uuid::new_v4(),
+                resumed: false,
+            };
+            framed.send(authorized).await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let config = ReconnectConfig::default();
+        let mut client = ReconnectingClient::connect(
+            &server_addr,
+            Some(uuid::Uuid::new_v4()),
+            None,
+            None,
+            config.clone(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            client.next_event().await,
+            Some(ConnectionEvent::Connected(_))
+        ));
+
+        // Наши Ping уходят каждые heartbeat_interval, но тишину сервера они не продлевают
+        tokio::time::pause();
+        let silent_since = tokio::time::Instant::now();
+        assert!(matches!(
+            client.next_event().await,
+            Some(ConnectionEvent::Disconnected { .. })
+        ));
+        let waited = silent_since.elapsed();
+        assert!(waited > config.heartbeat_timeout - config.heartbeat_interval);
+        assert!(waited < config.heartbeat_timeout + config.heartbeat_interval);
+    }
+}
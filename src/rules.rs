@@ -0,0 +1,216 @@
+// Правила игры.
+//
+// Изначально правило было зашито прямо в обработчик Flash на сервере: кто первый
+// успешно достал сообщение из MessageStore, тот и победил. Здесь это правило вынесено
+// в трейт, чтобы можно было пробовать другие варианты игры, не трогая сетевую часть.
+// Сервер спрашивает у правил, можно ли принять Content, может ли игрок претендовать
+// на сообщение, кто из претендентов победил и сколько очков ему начислить.
+
+use std::time::Duration;
+
+use crate::protocol::ErrorReason;
+use crate::StoredMessage;
+
+// Одна попытка забрать сообщение
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlashAttempt {
+    pub player_id: uuid::Uuid,
+    // Сколько прошло от броадкаста сообщения до прихода Flash
+    pub latency: Duration,
+}
+
+// Когда определяется победитель
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    // Сразу на каждом Flash
+    Immediate,
+    // Собираем попытки, а победителя выбираем, когда с момента броадкаста пройдет заданное время
+    AfterWindow(Duration),
+}
+
+pub trait GameRules: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // Проверка Content до броадкаста, по умолчанию принимаем все
+    fn validate_content(&self, _msg_id: uuid::Uuid, _body: &[u8]) -> Result<(), ErrorReason> {
+        Ok(())
+    }
+
+    // Может ли игрок вообще претендовать на это сообщение
+    fn check_flash(
+        &self,
+        message: &StoredMessage,
+        attempt: &FlashAttempt,
+    ) -> Result<(), ErrorReason>;
+
+    fn resolution(&self) -> Resolution;
+
+    // Индекс победителя среди попыток (попытки идут в порядке прихода)
+    fn select_winner(&self, attempts: &[FlashAttempt]) -> Option<usize>;
+
+    // Сколько базовых очков стоит сообщение, бонусы сверху начисляет scoring::ScoringModel
+    fn score(&self, _message: &StoredMessage, _winner: &FlashAttempt) -> u32 {
+        1
+    }
+}
+
+// Общие для всех правил проверки: автор не может забрать свое сообщение,
+// а остальные могут бороться только за то, что им действительно доставили
+fn check_recipient(message: &StoredMessage, attempt: &FlashAttempt) -> Result<(), ErrorReason> {
+    if message.author == attempt.player_id {
+        Err(ErrorReason::OwnMessage)
+    } else if !message.recipients.contains(&attempt.player_id) {
+        Err(ErrorReason::NotRecipient)
+    } else {
+        Ok(())
+    }
+}
+
+// Правило из ТЗ: побеждает первый Flash, пришедший не раньше минимального времени реакции
+pub struct FirstFlashWins {
+    pub min_flash_delay: Duration,
+}
+
+impl GameRules for FirstFlashWins {
+    fn name(&self) -> &'static str {
+        "first_flash"
+    }
+
+    fn check_flash(
+        &self,
+        message: &StoredMessage,
+        attempt: &FlashAttempt,
+    ) -> Result<(), ErrorReason> {
+        check_recipient(message, attempt)?;
+
+        if attempt.latency < self.min_flash_delay {
+            return Err(ErrorReason::TooEarly);
+        }
+
+        Ok(())
+    }
+
+    fn resolution(&self) -> Resolution {
+        Resolution::Immediate
+    }
+
+    fn select_winner(&self, attempts: &[FlashAttempt]) -> Option<usize> {
+        if attempts.is_empty() {
+            None
+        } else {
+            Some(0)
+        }
+    }
+}
+
+// Альтернативное правило: побеждает тот, чья задержка ближе всего к целевой.
+// Сообщение открыто для Flash в течение window с момента броадкаста, после чего
+// сервер выбирает победителя среди всех пришедших попыток. Так выигрывает
+// не самый быстрый канал, а самый точный таймер.
+pub struct ClosestToTargetWins {
+    pub min_flash_delay: Duration,
+    pub target_delay: Duration,
+    pub window: Duration,
+}
+
+impl GameRules for ClosestToTargetWins {
+    fn name(&self) -> &'static str {
+        "closest_to_target"
+    }
+
+    fn check_flash(
+        &self,
+        message: &StoredMessage,
+        attempt: &FlashAttempt,
+    ) -> Result<(), ErrorReason> {
+        check_recipient(message, attempt)?;
+
+        if attempt.latency < self.min_flash_delay {
+            return Err(ErrorReason::TooEarly);
+        }
+
+        if attempt.latency > self.window {
+            return Err(ErrorReason::TooLate);
+        }
+
+        // Каждый игрок делает только одну попытку, иначе можно было бы закидать сервер
+        // Flash'ами с разными задержками и гарантированно попасть в цель
+        if message
+            .attempts
+            .iter()
+            .any(|previous| previous.player_id == attempt.player_id)
+        {
+            return Err(ErrorReason::AlreadyFlashed);
+        }
+
+        Ok(())
+    }
+
+    fn resolution(&self) -> Resolution {
+        Resolution::AfterWindow(self.window)
+    }
+
+    // min_by_key возвращает первый из равных, так что при равенстве побеждает тот, кто прислал раньше
+    fn select_winner(&self, attempts: &[FlashAttempt]) -> Option<usize> {
+        attempts
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, attempt)| attempt.latency.abs_diff(self.target_delay))
+            .map(|(index, _)| index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attempt(latency_ms: u64) -> FlashAttempt {
+        FlashAttempt {
+            player_id: uuid::Uuid::new_v4(),
+            latency: Duration::from_millis(latency_ms),
+        }
+    }
+
+    #[test]
+    fn test_closest_to_target_selects_nearest_latency() {
+        let rules = ClosestToTargetWins {
+            min_flash_delay: Duration::from_millis(1000),
+            target_delay: Duration::from_millis(1375),
+            window: Duration::from_millis(2000),
+        };
+
+        let attempts = [attempt(1250), attempt(1400), attempt(1500), attempt(1350)];
+        assert_eq!(rules.select_winner(&attempts), Some(1));
+        assert_eq!(rules.select_winner(&[]), None);
+    }
+
+    #[test]
+    fn test_closest_to_target_allows_single_attempt() {
+        let rules = ClosestToTargetWins {
+            min_flash_delay: Duration::from_millis(1000),
+            target_delay: Duration::from_millis(1375),
+            window: Duration::from_millis(2000),
+        };
+
+        let first = attempt(1300);
+        let mut message = StoredMessage::new(
+            uuid::Uuid::new_v4(),
+            uuid::Uuid::new_v4(),
+            vec![],
+            std::time::Instant::now(),
+            [first.player_id].into_iter().collect(),
+        );
+
+        assert_eq!(rules.check_flash(&message, &first), Ok(()));
+        message.attempts.push(first);
+
+        let second = FlashAttempt {
+            latency: Duration::from_millis(1375),
+            ..first
+        };
+        assert_eq!(
+            rules.check_flash(&message, &second),
+            Err(ErrorReason::AlreadyFlashed)
+        );
+    }
+}
@@ -0,0 +1,151 @@
+// Подсчет очков и рейтинга.
+//
+// Изначально каждая победа стоила одно очко, и таблица по сути показывала, кто дольше
+// всех сидит на сервере. Теперь базовые очки назначают правила игры, а модель добавляет
+// к ним бонус за быструю реакцию и за серию побед подряд, и уменьшает награду за
+// сообщения, которые долго висели без победителя. По умолчанию все бонусы выключены,
+// и очки совпадают с базой.
+//
+// Отдельно можно включить рейтинг Эло: каждая победа считается выигранной партией
+// против всех остальных получателей сообщения.
+
+use std::time::Duration;
+
+// С этого рейтинга начинает каждый новый игрок
+pub const INITIAL_RATING: f64 = 1500.0;
+
+#[derive(Debug, Clone, Default)]
+pub struct ScoringModel {
+    // Задержка, раньше которой Flash не принимается правилами. От нее считаем скорость
+    pub min_latency: Duration,
+    // Максимальный бонус за скорость, его получает Flash ровно на min_latency.
+    // Дальше бонус линейно падает до нуля за fast_window
+    pub fast_bonus: u32,
+    pub fast_window: Duration,
+    // Бонус за каждую победу подряд в комнате после первой, серия учитывается не длиннее max_streak
+    pub streak_bonus: u32,
+    pub max_streak: u32,
+    // Каждый такой период от броадкаста до победы уменьшает очки вдвое
+    pub age_half_life: Option<Duration>,
+    pub elo: Option<Elo>,
+}
+
+impl ScoringModel {
+    // streak это длина серии вместе с текущей победой, age это возраст сообщения в момент победы
+    pub fn points(&self, base: u32, latency: Duration, streak: u32, age: Duration) -> u32 {
+        let mut points = base as f64;
+
+        let reaction = latency.saturating_sub(self.min_latency);
+        if self.fast_bonus > 0 && reaction < self.fast_window {
+            let left = self.fast_window - reaction;
+            points += self.fast_bonus as f64 * left.as_secs_f64() / self.fast_window.as_secs_f64();
+        }
+
+        let streak = streak.saturating_sub(1).min(self.max_streak);
+        points += self.streak_bonus as f64 * streak as f64;
+
+        if let Some(half_life) = self.age_half_life {
+            points *= 0.5f64.powf(age.as_secs_f64() / half_life.as_secs_f64());
+        }
+
+        // Победа всегда чего-то стоит, иначе старые сообщения никто не стал бы забирать
+        (points.round() as u32).max(1)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Elo {
+    pub k_factor: f64,
+}
+
+impl Elo {
+    // Ожидаемый результат игрока с рейтингом rating против соперника с рейтингом opponent
+    fn expected(rating: f64, opponent: f64) -> f64 {
+        1.0 / (1.0 + 10f64.powf((opponent - rating) / 400.0))
+    }
+
+    // Новые рейтинги победителя и проигравших. K делится поровну между всеми партиями,
+    // чтобы победа в комнате на сотню игроков не стоила в сто раз больше, чем на двоих.
+    // Сумма рейтингов при этом не меняется.
+    pub fn update(&self, winner: f64, losers: &[f64]) -> (f64, Vec<f64>) {
+        if losers.is_empty() {
+            return (winner, vec![]);
+        }
+
+        let k = self.k_factor / losers.len() as f64;
+        let mut new_winner = winner;
+        let new_losers = losers
+            .iter()
+            .map(|&loser| {
+                let delta = k * (1.0 - Elo::expected(winner, loser));
+                new_winner += delta;
+                loser - delta
+            })
+            .collect();
+
+        (new_winner, new_losers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_points_with_bonuses_and_decay() {
+        let model = ScoringModel {
+            min_latency: Duration::from_millis(1000),
+            fast_bonus: 4,
+            fast_window: Duration::from_millis(500),
+            streak_bonus: 1,
+            max_streak: 2,
+            age_half_life: Some(Duration::from_secs(10)),
+            elo: None,
+        };
+
+        let fresh = Duration::ZERO;
+        assert_eq!(model.points(1, Duration::from_millis(1000), 1, fresh), 5);
+        assert_eq!(model.points(1, Duration::from_millis(1250), 1, fresh), 3);
+        assert_eq!(model.points(1, Duration::from_millis(1600), 1, fresh), 1);
+        // Серия дает не больше max_streak бонусов
+        assert_eq!(model.points(1, Duration::from_millis(1600), 10, fresh), 3);
+        // Через период полураспада очки вдвое меньше, но не меньше одного
+        assert_eq!(
+            model.points(1, Duration::from_millis(1000), 1, Duration::from_secs(10)),
+            3
+        );
+        assert_eq!(
+            model.points(1, Duration::from_millis(1600), 1, Duration::from_secs(60)),
+            1
+        );
+
+        assert_eq!(
+            ScoringModel::default().points(2, Duration::ZERO, 3, fresh),
+            2
+        );
+
+        // Огромный бонус из env не переполняется, а упирается в максимум
+        let greedy = ScoringModel {
+            streak_bonus: u32::MAX,
+            max_streak: u32::MAX,
+            ..ScoringModel::default()
+        };
+        assert_eq!(greedy.points(1, Duration::ZERO, 10, fresh), u32::MAX);
+    }
+
+    #[test]
+    fn test_elo_update_is_zero_sum() {
+        let elo = Elo { k_factor: 32.0 };
+
+        let (winner, losers) = elo.update(INITIAL_RATING, &[INITIAL_RATING]);
+        assert_eq!(winner, INITIAL_RATING + 16.0);
+        assert_eq!(losers, vec![INITIAL_RATING - 16.0]);
+
+        // Победа над слабыми приносит меньше, чем над сильными
+        let (against_weak, _) = elo.update(INITIAL_RATING, &[1200.0, 1200.0]);
+        let (against_strong, losers) = elo.update(INITIAL_RATING, &[1800.0, 1800.0]);
+        assert!(against_weak - INITIAL_RATING < against_strong - INITIAL_RATING);
+        let total = against_strong + losers.iter().sum::<f64>();
+        assert!((total - (INITIAL_RATING + 3600.0)).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,100 @@
+// Архив завершенных сезонов.
+//
+// Живые счетчики игроков сбрасываются в конце каждого сезона, а итоговая таблица
+// складывается сюда, чтобы ее можно было запросить через API и после сброса.
+// Храним все сезоны в памяти, как и остальную статистику сервера.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeasonStanding {
+    pub player_id: uuid::Uuid,
+    pub wins: u32,
+    pub score: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ArchivedSeason {
+    pub season: u32,
+    // Unix время в миллисекундах, как и в WinLogStore
+    pub started_at: u128,
+    pub ended_at: u128,
+    // Отсортированы от лидера к аутсайдеру
+    pub standings: Vec<SeasonStanding>,
+}
+
+#[derive(Default)]
+pub struct SeasonArchive {
+    seasons: Vec<ArchivedSeason>,
+}
+
+impl SeasonArchive {
+    pub fn new() -> Self {
+        SeasonArchive::default()
+    }
+
+    pub fn archive(
+        &mut self,
+        season: u32,
+        started_at: u128,
+        ended_at: u128,
+        mut standings: Vec<SeasonStanding>,
+    ) {
+        standings.sort_by(|a, b| b.score.cmp(&a.score).then_with(|| b.wins.cmp(&a.wins)));
+
+        self.seasons.push(ArchivedSeason {
+            season,
+            started_at,
+            ended_at,
+            standings,
+        });
+    }
+
+    pub fn get(&self, season: u32) -> Option<&ArchivedSeason> {
+        // Сезоны добавляются по порядку, так что можно искать бинарным поиском
+        self.seasons
+            .binary_search_by_key(&season, |archived| archived.season)
+            .ok()
+            .map(|index| &self.seasons[index])
+    }
+
+    pub fn all(&self) -> &[ArchivedSeason] {
+        &self.seasons
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_archive_sorts_standings_and_finds_season() {
+        let leader = uuid::Uuid::new_v4();
+        let runner_up = uuid::Uuid::new_v4();
+
+        let mut archive = SeasonArchive::new();
+        archive.archive(1, 0, 10, vec![]);
+        archive.archive(
+            2,
+            10,
+            20,
+            vec![
+                SeasonStanding {
+                    player_id: runner_up,
+                    wins: 5,
+                    score: 5,
+                },
+                SeasonStanding {
+                    player_id: leader,
+                    wins: 4,
+                    score: 9,
+                },
+            ],
+        );
+
+        let season = archive.get(2).unwrap();
+        assert_eq!(season.started_at, 10);
+        assert_eq!(season.standings[0].player_id, leader);
+        assert_eq!(season.standings[1].player_id, runner_up);
+        assert!(archive.get(3).is_none());
+        assert_eq!(archive.all().len(), 2);
+    }
+}
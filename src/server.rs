@@ -0,0 +1,3212 @@
+// Игровой сервер.
+//
+// Сам сервер живет в библиотеке, чтобы его одинаково запускали бинарь server и
+// nltt server. Настраивается он целиком через переменные окружения, см. Config.
+
+use std::env;
+use std::{collections::HashMap, sync::Arc};
+
+use futures::SinkExt;
+use linked_hash_map::LinkedHashMap;
+use tokio::sync::Mutex;
+use tokio_stream::StreamExt;
+
+use crate::access::AccessList;
+use crate::auth::{ApiKeys, Scope};
+use crate::latency::LatencyStats;
+use crate::metrics::{Lock, Metrics};
+use crate::players::PlayerRegistry;
+use crate::protocol;
+use crate::ratelimit::{RateLimit, TokenBucket};
+use crate::rules::{FlashAttempt, GameRules};
+use crate::scoring::ScoringModel;
+use crate::seasons::{SeasonArchive, SeasonStanding};
+use crate::validation::ContentPolicy;
+use crate::FlashOutcome;
+use crate::MessageStore;
+use crate::StoredMessage;
+use crate::WinLogStore;
+
+// Настройки сервера, читаются из переменных окружения при старте.
+// Лимиты можно поменять на лету командой SetLimit из админки
+#[derive(Clone)]
+struct Config {
+    // После скольких ранних Flash начинаем снимать по одной победе за каждый следующий
+    early_flash_penalty_after: Option<u32>,
+    // После скольких ранних Flash игрок банится до перезапуска сервера
+    early_flash_ban_after: Option<u32>,
+    // Длительность сезона. Если не задана, то сезон один и длится, пока живет сервер
+    round_duration: Option<std::time::Duration>,
+    // Сколько сообщение можно выиграть после броадкаста. Если не задано, живет, пока не вытеснят
+    message_ttl: Option<std::time::Duration>,
+    // Рассылать ли всей комнате MessageClaimed, когда у сообщения появился победитель
+    announce_claims: bool,
+    // Лимиты частоты Content и Flash на одного игрока. None - без ограничений
+    content_limit: Option<RateLimit>,
+    flash_limit: Option<RateLimit>,
+    // Сколько нарушений лимитов за rate_limit_window терпим, прежде чем отключить игрока
+    rate_limit_disconnect_after: Option<u32>,
+    rate_limit_window: std::time::Duration,
+    // Какие тела Content принимаем
+    content_policy: ContentPolicy,
+    // Файл со списками доступа, см. nltt::access
+    access_list_file: Option<String>,
+    // Пускать только подписи, выданные через Register. Иначе, как и раньше,
+    // любая новая подпись в Authorize молча заводит нового игрока
+    require_registration: bool,
+    // Файл с публичными id игроков, см. nltt::players. Без него id меняются при перезапуске
+    players_file: Option<String>,
+    // Register не требует подписи, поэтому ограничиваем его частоту по адресу
+    register_limit: Option<RateLimit>,
+    // Сколько после обрыва держим сессию игрока, чтобы он мог продолжить ее по resume_token.
+    // Все, что приходит игроку за это время, копится в его буфере
+    resume_grace: std::time::Duration,
+    // Как часто пингуем игрока и сколько ждем от него хоть какого-то фрейма, прежде чем
+    // считать соединение мертвым. HEARTBEAT_INTERVAL_MS=0 выключает пинги
+    heartbeat_interval: Option<std::time::Duration>,
+    heartbeat_timeout: std::time::Duration,
+    // Больше комнат не создаем, комнаты создаются игроками, и иначе их число ничем не ограничено
+    max_rooms: usize,
+}
+
+impl Config {
+    fn from_env() -> Self {
+        Config {
+            early_flash_penalty_after: optional_env("EARLY_FLASH_PENALTY_AFTER"),
+            early_flash_ban_after: optional_env("EARLY_FLASH_BAN_AFTER"),
+            round_duration: optional_env("ROUND_DURATION_SECS").map(std::time::Duration::from_secs),
+            message_ttl: optional_env("MESSAGE_TTL_MS").map(std::time::Duration::from_millis),
+            announce_claims: optional_env("ANNOUNCE_CLAIMS").unwrap_or(false),
+            content_limit: rate_limit_from_env("CONTENT"),
+            flash_limit: rate_limit_from_env("FLASH"),
+            rate_limit_disconnect_after: optional_env("RATE_LIMIT_DISCONNECT_AFTER"),
+            rate_limit_window: std::time::Duration::from_secs(
+                optional_env("RATE_LIMIT_WINDOW_SECS").unwrap_or(10),
+            ),
+            content_policy: content_policy_from_env(),
+            access_list_file: env::var("ACCESS_LIST_FILE").ok(),
+            require_registration: optional_env("REQUIRE_REGISTRATION").unwrap_or(false),
+            players_file: env::var("PLAYERS_FILE").ok(),
+            // Без настройки пачка из 10 регистраций, дальше одна в 5 секунд
+            register_limit: rate_limit_from_env("REGISTER").or(Some(RateLimit {
+                per_sec: 0.2,
+                burst: 10.0,
+            })),
+            resume_grace: std::time::Duration::from_millis(
+                optional_env("RESUME_GRACE_MS").unwrap_or(10_000),
+            ),
+            heartbeat_interval: Some(optional_env("HEARTBEAT_INTERVAL_MS").unwrap_or(5_000))
+                .filter(|interval| *interval > 0)
+                .map(std::time::Duration::from_millis),
+            heartbeat_timeout: std::time::Duration::from_millis(
+                optional_env("HEARTBEAT_TIMEOUT_MS").unwrap_or(15_000),
+            ),
+            max_rooms: optional_env("MAX_ROOMS").unwrap_or(1_000),
+        }
+    }
+
+    // Имена и формат значений те же, что и у переменных окружения, None снимает лимит
+    fn set_limit(&mut self, name: &str, value: Option<&str>) -> Result<(), String> {
+        fn parse<T: std::str::FromStr>(
+            name: &str,
+            value: Option<&str>,
+        ) -> Result<Option<T>, String> {
+            value
+                .map(|value| {
+                    value
+                        .parse::<T>()
+                        .map_err(|_| format!("{} value {} is not valid", name, value))
+                })
+                .transpose()
+        }
+
+        match name {
+            "EARLY_FLASH_PENALTY_AFTER" => self.early_flash_penalty_after = parse(name, value)?,
+            "EARLY_FLASH_BAN_AFTER" => self.early_flash_ban_after = parse(name, value)?,
+            "RATE_LIMIT_DISCONNECT_AFTER" => self.rate_limit_disconnect_after = parse(name, value)?,
+            "RATE_LIMIT_WINDOW_SECS" => {
+                self.rate_limit_window =
+                    std::time::Duration::from_secs(parse(name, value)?.unwrap_or(10))
+            }
+            "CONTENT_RATE_PER_SEC" => set_rate(&mut self.content_limit, parse(name, value)?),
+            "FLASH_RATE_PER_SEC" => set_rate(&mut self.flash_limit, parse(name, value)?),
+            "CONTENT_BURST" => set_burst(&mut self.content_limit, parse(name, value)?)?,
+            "FLASH_BURST" => set_burst(&mut self.flash_limit, parse(name, value)?)?,
+            "REGISTER_RATE_PER_SEC" => set_rate(&mut self.register_limit, parse(name, value)?),
+            "REGISTER_BURST" => set_burst(&mut self.register_limit, parse(name, value)?)?,
+            other => return Err(format!("unknown limit {}", other)),
+        }
+
+        Ok(())
+    }
+}
+
+fn set_rate(limit: &mut Option<RateLimit>, per_sec: Option<f64>) {
+    *limit = per_sec.map(|per_sec| RateLimit {
+        per_sec,
+        burst: limit.map(|limit| limit.burst).unwrap_or(5.0),
+    });
+}
+
+fn set_burst(limit: &mut Option<RateLimit>, burst: Option<f64>) -> Result<(), String> {
+    match limit {
+        Some(limit) => {
+            limit.burst = burst.unwrap_or(5.0);
+            Ok(())
+        }
+        None => Err("rate limit is disabled, set the rate first".to_string()),
+    }
+}
+
+// По умолчанию проверяем только длину 30..=100 байт из ТЗ
+fn content_policy_from_env() -> ContentPolicy {
+    let default = ContentPolicy::default();
+    ContentPolicy {
+        min_len: optional_env("CONTENT_MIN_BYTES").unwrap_or(default.min_len),
+        max_len: optional_env("CONTENT_MAX_BYTES").unwrap_or(default.max_len),
+        charset: optional_env("CONTENT_CHARSET").unwrap_or(default.charset),
+        require_utf8: optional_env("CONTENT_REQUIRE_UTF8").unwrap_or(default.require_utf8),
+    }
+}
+
+// Ключи API из API_KEYS_FILE, ADMIN_TOKEN по старой памяти считается ключом с правом admin
+fn api_keys_from_env() -> ApiKeys {
+    let mut api_keys = match env::var("API_KEYS_FILE") {
+        Ok(path) => ApiKeys::load(&path).unwrap_or_else(|e| panic!("{}", e)),
+        Err(_) => ApiKeys::new(),
+    };
+    if let Ok(admin_token) = env::var("ADMIN_TOKEN") {
+        api_keys.insert(&admin_token, [Scope::Admin].into());
+    }
+    if api_keys.is_empty() {
+        log::warn!("No API keys configured, API server is open for reading to everyone");
+    }
+    api_keys
+}
+
+// Какое право нужно для запроса к API. None - запрос не требует прав
+fn required_scope(frame: &protocol::PupaFrame) -> Option<Scope> {
+    match frame {
+        protocol::PupaFrame::ShowWinnersLog => Some(Scope::ReadWinlog),
+        protocol::PupaFrame::ShowWinners
+        | protocol::PupaFrame::ShowLatencies
+        | protocol::PupaFrame::ShowRoomWinners { .. }
+        | protocol::PupaFrame::ShowSeasons
+        | protocol::PupaFrame::ShowSeasonWinners { .. }
+        | protocol::PupaFrame::ShowProfile { .. }
+        | protocol::PupaFrame::ListRooms => Some(Scope::ReadLeaderboard),
+        protocol::PupaFrame::Admin { .. } => Some(Scope::Admin),
+        _ => None,
+    }
+}
+
+// <PREFIX>_RATE_PER_SEC включает лимит, <PREFIX>_BURST задает размер пачки (по умолчанию 5)
+fn rate_limit_from_env(prefix: &str) -> Option<RateLimit> {
+    optional_env(&format!("{}_RATE_PER_SEC", prefix)).map(|per_sec| RateLimit {
+        per_sec,
+        burst: optional_env(&format!("{}_BURST", prefix)).unwrap_or(5.0),
+    })
+}
+
+// Правила игры выбираются переменной GAME_RULES, по умолчанию играем по ТЗ
+fn rules_from_env() -> Box<dyn GameRules> {
+    // Спецификация требует от клиента ждать 1с + 250..500мс перед Flash.
+    // Все, что пришло быстрее этого порога, мы не засчитываем.
+    let min_flash_delay =
+        std::time::Duration::from_millis(optional_env("MIN_FLASH_DELAY_MS").unwrap_or(1_000));
+
+    match env::var("GAME_RULES").as_deref() {
+        Err(_) | Ok("first_flash") => Box::new(crate::rules::FirstFlashWins { min_flash_delay }),
+        // По умолчанию целимся в середину интервала из ТЗ: 1с + (250..500)/2
+        Ok("closest_to_target") => Box::new(crate::rules::ClosestToTargetWins {
+            min_flash_delay,
+            target_delay: std::time::Duration::from_millis(
+                optional_env("TARGET_FLASH_DELAY_MS").unwrap_or(1_375),
+            ),
+            window: std::time::Duration::from_millis(
+                optional_env("FLASH_WINDOW_MS").unwrap_or(2_000),
+            ),
+        }),
+        Ok(other) => panic!(
+            "GAME_RULES environment variable has unknown value {}",
+            other
+        ),
+    }
+}
+
+// Бонусы к очкам и рейтинг Эло. По умолчанию все выключено, и победа стоит столько,
+// сколько назначили правила игры
+fn scoring_from_env() -> ScoringModel {
+    ScoringModel {
+        min_latency: std::time::Duration::from_millis(
+            optional_env("MIN_FLASH_DELAY_MS").unwrap_or(1_000),
+        ),
+        fast_bonus: optional_env("SCORE_FAST_BONUS").unwrap_or(0),
+        fast_window: std::time::Duration::from_millis(
+            optional_env("SCORE_FAST_WINDOW_MS").unwrap_or(500),
+        ),
+        streak_bonus: optional_env("SCORE_STREAK_BONUS").unwrap_or(0),
+        max_streak: optional_env("SCORE_MAX_STREAK").unwrap_or(5),
+        age_half_life: optional_env("SCORE_AGE_HALF_LIFE_MS").map(std::time::Duration::from_millis),
+        elo: optional_env("ELO_K_FACTOR").map(|k_factor| crate::scoring::Elo { k_factor }),
+    }
+}
+
+// Все общее состояние сервера, которое раздается хэндлерам.
+// Можно было бы message_stores положить в State, но у нас тогда была бы общая
+// write блокировка на добавляение новых peer и на запись сообщений в очередь,
+// поэтому каждое хранилище живет под своим мьютексом.
+//
+// У каждой комнаты свой MessageStore, чтобы шумная комната не вытесняла
+// сообщения из тихой. Все сторы под одним мьютексом, операции с ними короткие.
+struct Context {
+    state: Mutex<State>,
+    message_stores: Mutex<MessageStores>,
+    winlog_store: Mutex<WinLogStore>,
+    metrics: Arc<Metrics>,
+    // Админка может поменять лимиты на лету. Лок обычный, так что гард через await не держим
+    config: std::sync::RwLock<Config>,
+    // Проверяется только на Authorize, так что обычного RwLock хватает
+    access: std::sync::RwLock<AccessList>,
+    // Файл списков доступа читают и пишут по очереди, чтобы перечитывание не затерло
+    // свежий бан из админки. Лок отдельный: держать State, пока идет работа с диском, нельзя
+    access_file: Mutex<()>,
+    api_keys: ApiKeys,
+    rules: Box<dyn GameRules>,
+    scoring: ScoringModel,
+}
+
+impl Context {
+    fn config(&self) -> std::sync::RwLockReadGuard<'_, Config> {
+        self.config.read().unwrap()
+    }
+
+    // Перечитываем списки доступа и отключаем тех, кто по новым спискам уже не проходит
+    async fn reload_access_list(&self) -> Result<String, String> {
+        let Some(path) = self.config().access_list_file.clone() else {
+            return Err("ACCESS_LIST_FILE is not set".to_string());
+        };
+        let _access_file = self.access_file.lock().await;
+        let access = {
+            let path = path.clone();
+            tokio::task::spawn_blocking(move || AccessList::load(&path))
+                .await
+                .map_err(|e| e.to_string())??
+        };
+
+        let mut state = self.state().await;
+        let denied = state
+            .peers
+            .values()
+            .filter(|peer| peer.online)
+            .filter_map(|peer| {
+                access
+                    .check(peer.signature, peer.address.ip())
+                    .err()
+                    .map(|reason| (peer.player_id, reason))
+            })
+            .collect::<Vec<_>>();
+        let mut kicked = 0;
+        for (player_id, reason) in denied {
+            log::info!(
+                "User {} is denied by the access list | {:?}",
+                player_id,
+                reason
+            );
+            if state.kick(player_id, "denied by the access list") {
+                kicked += 1;
+            }
+        }
+        *self.access.write().unwrap() = access;
+
+        Ok(format!("{} is reloaded, {} peers kicked", path, kicked))
+    }
+
+    // Бан админки пишем по подписи в ACCESS_LIST_FILE, так он переживет и перезапуск,
+    // и reload-access. false, если файла нет и бан живет только до перезапуска
+    async fn save_ban(&self, signature: uuid::Uuid, banned: bool) -> Result<bool, String> {
+        let Some(path) = self.config().access_list_file.clone() else {
+            return Ok(false);
+        };
+        let _access_file = self.access_file.lock().await;
+        tokio::task::spawn_blocking(move || AccessList::save_ban(&path, signature, banned))
+            .await
+            .map_err(|e| e.to_string())??;
+        self.access.write().unwrap().set_banned(signature, banned);
+        Ok(true)
+    }
+
+    async fn state(&self) -> tokio::sync::MutexGuard<'_, State> {
+        self.metrics.lock(Lock::State, &self.state).await
+    }
+
+    async fn message_stores(&self) -> tokio::sync::MutexGuard<'_, MessageStores> {
+        self.metrics
+            .lock(Lock::MessageStore, &self.message_stores)
+            .await
+    }
+
+    // Размер всех сторов вместе, комнат немного, так что просто пробегаемся по ним
+    fn update_message_store_size(&self, message_stores: &MessageStores) {
+        self.metrics.message_store_size.set(
+            message_stores
+                .rooms
+                .values()
+                .map(|message_store| message_store.len() as i64)
+                .sum(),
+        );
+    }
+
+    async fn winlog_store(&self) -> tokio::sync::MutexGuard<'_, WinLogStore> {
+        self.metrics
+            .lock(Lock::WinLogStore, &self.winlog_store)
+            .await
+    }
+
+    // Начисляем победу во все хранилища и возвращаем фрейм, который нужно отдать победителю
+    async fn award_win(
+        &self,
+        room: &str,
+        message: StoredMessage,
+        attempt: FlashAttempt,
+    ) -> protocol::PupaFrame {
+        let latency_ms = attempt.latency.as_millis() as u64;
+        let base = self.rules.score(&message, &attempt);
+
+        self.metrics.wins.inc();
+        {
+            let mut state = self.state().await;
+            let streak = state.record_streak(room, attempt.player_id);
+            let points = self.scoring.points(
+                base,
+                attempt.latency,
+                streak,
+                message.broadcast_at.elapsed(),
+            );
+            state.update_winners(attempt.player_id, room, latency_ms, points);
+
+            if let Some(elo) = &self.scoring.elo {
+                state.update_ratings(elo, attempt.player_id, &message.recipients);
+            }
+
+            // Остальным претендентам сообщаем, что они проиграли. Попытки есть только
+            // у правил с окном, при мгновенном выборе проигравшие узнают о победителе на своем Flash
+            for loser in message
+                .attempts
+                .iter()
+                .filter(|loser| loser.player_id != attempt.player_id)
+            {
+                state.send_to(
+                    loser.player_id,
+                    protocol::PupaFrame::Lost {
+                        msg_id: message.msg_id,
+                        winner: attempt.player_id,
+                        winner_latency: latency_ms,
+                    },
+                );
+            }
+
+            if self.config().announce_claims {
+                state.notify(
+                    Some(room),
+                    Some(attempt.player_id),
+                    protocol::PupaFrame::MessageClaimed {
+                        msg_id: message.msg_id,
+                        winner: attempt.player_id,
+                    },
+                );
+            }
+        }
+        self.winlog_store().await.insert(
+            message.msg_id,
+            attempt.player_id,
+            latency_ms,
+            room.to_string(),
+        );
+
+        log::info!(
+            "User {} is a winner for the message \"{}\" in room {} in {}ms",
+            attempt.player_id,
+            message.msg_id,
+            room,
+            latency_ms
+        );
+
+        protocol::PupaFrame::Win {
+            msg_id: message.msg_id,
+            body: message.body,
+        }
+    }
+}
+
+// Сторы комнат и msg_id, которые прямо сейчас рассылаются. Резерв живет под тем же
+// мьютексом, что и сторы, поэтому проверка на повтор и резерв это одна операция,
+// и два одинаковых Content, даже из разных комнат, не разошлются оба
+#[derive(Default)]
+struct MessageStores {
+    rooms: HashMap<String, MessageStore>,
+    // Прошли проверку на повтор, но в стор своей комнаты попадут только после броадкаста
+    reserved: std::collections::HashSet<uuid::Uuid>,
+    // msg_id из сторов забытых комнат. Комнату забыть можно, а разыграть ее сообщения заново нельзя
+    forgotten: LinkedHashMap<uuid::Uuid, ()>,
+}
+
+impl MessageStores {
+    // false, если msg_id уже разыгрывается, недавно разыгрывался или сейчас рассылается.
+    // msg_id проверяем по всем комнатам, иначе уже выигранное сообщение
+    // можно было бы разыграть еще раз, просто перейдя в другую комнату
+    fn reserve(&mut self, msg_id: uuid::Uuid) -> bool {
+        if self.forgotten.contains_key(&msg_id)
+            || self
+                .rooms
+                .values()
+                .any(|message_store| message_store.is_known(msg_id))
+        {
+            return false;
+        }
+        self.reserved.insert(msg_id)
+    }
+
+    // Стор комнаты, которую State забыл в join_room. Его msg_id помним, сколько и стор помнил бы
+    fn drop_room(&mut self, room: &str) {
+        let Some(message_store) = self.rooms.remove(room) else {
+            return;
+        };
+        for msg_id in message_store.known_ids() {
+            if self.forgotten.len() == 10_000 {
+                self.forgotten.pop_front();
+            }
+            self.forgotten.insert(msg_id, ());
+        }
+    }
+
+    fn release(&mut self, msg_id: uuid::Uuid) {
+        self.reserved.remove(&msg_id);
+    }
+}
+
+fn optional_env<T: std::str::FromStr>(name: &str) -> Option<T> {
+    env::var(name).ok().map(|value| {
+        value
+            .parse::<T>()
+            .unwrap_or_else(|_| panic!("{} environment variable is not valid", name))
+    })
+}
+
+// Чем закончился очередной ранний Flash для игрока
+#[derive(Debug, PartialEq, Eq)]
+enum EarlyFlashVerdict {
+    Warned,
+    Penalised,
+    Banned,
+}
+
+// Снимаем одну победу, а с ней и ее очки. Сколько стоила именно та победа, мы не помним,
+// поэтому берем среднее по всем победам
+fn take_win(wins: &mut u32, score: &mut u64) {
+    if *wins == 0 {
+        return;
+    }
+    *score -= *score / *wins as u64;
+    *wins -= 1;
+}
+
+// Какой фрейм проверяем на лимит
+#[derive(Debug, Clone, Copy)]
+enum LimitedFrame {
+    Content,
+    Flash,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum RateVerdict {
+    Allowed,
+    Limited,
+    Disconnect,
+}
+
+// Лимиты живут в Peer, а не в хэндлере, чтобы переподключение не давало полное ведро
+#[derive(Debug, Clone)]
+struct PlayerLimits {
+    content: Option<TokenBucket>,
+    flash: Option<TokenBucket>,
+    violations: u32,
+    violations_since: std::time::Instant,
+}
+
+impl PlayerLimits {
+    fn new(config: &Config) -> Self {
+        let now = std::time::Instant::now();
+        PlayerLimits {
+            content: config
+                .content_limit
+                .map(|limit| TokenBucket::new(limit, now)),
+            flash: config.flash_limit.map(|limit| TokenBucket::new(limit, now)),
+            violations: 0,
+            violations_since: now,
+        }
+    }
+}
+
+// Результаты игрока внутри одной комнаты
+#[derive(Debug, Clone, Copy, Default)]
+struct RoomStanding {
+    wins: u32,
+    score: u64,
+}
+
+struct State {
+    // Игроки по публичному id. Подпись это пароль игрока, поэтому наружу она не уходит,
+    // а выдаем ее обладателю постоянный публичный id
+    peers: HashMap<uuid::Uuid, Peer>,
+    players: PlayerRegistry,
+    // Лимиты Register по адресам. Подписи у регистрирующихся еще нет, считать больше не по чему
+    register_limits: HashMap<std::net::IpAddr, TokenBucket>,
+    // Комната появляется, когда в нее кто-то впервые зашел, и дальше живет вместе со
+    // своей таблицей, даже если все из нее вышли. Пустые комнаты без таблицы
+    // забываем, только когда упираемся в MAX_ROOMS, см. join_room.
+    rooms: HashMap<String, HashMap<uuid::Uuid, RoomStanding>>,
+    // Кто выиграл последнее сообщение в комнате и сколько раз подряд
+    streaks: HashMap<String, (uuid::Uuid, u32)>,
+    // Текущий сезон и момент его начала (монотонный для таймера и unix для архива)
+    season: u32,
+    season_started: std::time::Instant,
+    season_started_at: u128,
+    seasons: SeasonArchive,
+    // Сколько держим сессию отвалившегося игрока, см. Config::resume_grace
+    resume_grace: std::time::Duration,
+}
+
+impl State {
+    fn new(players: PlayerRegistry, resume_grace: std::time::Duration) -> Self {
+        State {
+            peers: HashMap::new(),
+            players,
+            register_limits: HashMap::new(),
+            rooms: HashMap::from([(protocol::DEFAULT_ROOM.to_string(), HashMap::new())]),
+            streaks: HashMap::new(),
+            season: 1,
+            season_started: std::time::Instant::now(),
+            season_started_at: crate::now_millis(),
+            seasons: SeasonArchive::new(),
+            resume_grace,
+        }
+    }
+
+    fn round_started_frame(&self, round_duration: std::time::Duration) -> protocol::PupaFrame {
+        protocol::PupaFrame::RoundStarted {
+            season: self.season,
+            remaining_ms: round_duration
+                .saturating_sub(self.season_started.elapsed())
+                .as_millis() as u64,
+        }
+    }
+
+    // Закрываем сезон: итоговую таблицу в архив, живые счетчики игроков и комнат в ноль.
+    // Счетчик ранних Flash и баны не трогаем, это не про соревнование, а про античит.
+    // Рейтинг тоже переживает сезон, он про умение игрока, а не про результат периода.
+    // Возвращаем номер закрытого сезона и его победителя.
+    pub fn end_season(&mut self) -> (u32, Option<uuid::Uuid>) {
+        let standings = self
+            .peers
+            .values()
+            .filter(|peer| peer.wins > 0 || peer.messages_sent > 0 || peer.messages_received > 0)
+            .map(|peer| SeasonStanding {
+                player_id: peer.player_id,
+                wins: peer.wins,
+                score: peer.score,
+            })
+            .collect::<Vec<_>>();
+
+        let ended_season = self.season;
+        self.seasons.archive(
+            ended_season,
+            self.season_started_at,
+            crate::now_millis(),
+            standings,
+        );
+        let winner = self
+            .seasons
+            .get(ended_season)
+            .and_then(|archived| archived.standings.first())
+            .filter(|standing| standing.wins > 0)
+            .map(|standing| standing.player_id);
+
+        for peer in self.peers.values_mut() {
+            peer.messages_received = 0;
+            peer.messages_sent = 0;
+            peer.wins = 0;
+            peer.score = 0;
+            peer.win_latencies = LatencyStats::new();
+        }
+        for standings in self.rooms.values_mut() {
+            standings.clear();
+        }
+        self.streaks.clear();
+
+        self.season += 1;
+        self.season_started = std::time::Instant::now();
+        self.season_started_at = crate::now_millis();
+
+        (ended_season, winner)
+    }
+
+    // Служебный фрейм всем, кто сейчас онлайн в комнате (или вообще на сервере, если комната не задана).
+    // В отличие от broadcast не трогает счетчики сообщений и метрики Content
+    fn notify(
+        &mut self,
+        room: Option<&str>,
+        except: Option<uuid::Uuid>,
+        frame: protocol::PupaFrame,
+    ) {
+        for peer in self.peers.values_mut() {
+            if !peer.online
+                || room.is_some_and(|room| room != peer.room)
+                || except == Some(peer.player_id)
+            {
+                continue;
+            }
+            peer.deliver(frame.clone(), self.resume_grace);
+        }
+    }
+
+    // Публичный id уже знакомой подписи
+    pub fn player_id(&self, signature: uuid::Uuid) -> Option<uuid::Uuid> {
+        self.players.get(signature)
+    }
+
+    pub fn is_registered(&self, signature: uuid::Uuid) -> bool {
+        self.player_id(signature).is_some()
+    }
+
+    // Подпись прошла все проверки. Только теперь запоминаем ее, а новой выдаем id,
+    // иначе каждая отклоненная подпись навсегда оставалась бы у нас
+    fn admit(&mut self, peer: &mut Peer) {
+        let (player_id, profile) = self.players.admit(peer.signature, peer.name.take());
+        peer.player_id = player_id;
+        peer.name = profile.name;
+        peer.avatar = profile.avatar;
+    }
+
+    // Попытаемся найти старого peer с таким же ключом, вдруг он уже у нас был
+    // если был, то тогда заберем его старую статистику сюда.
+    // Новая сессия всегда начинается в комнате по умолчанию, а продолженная по
+    // resume_token остается в своей комнате и забирает все, что накопилось за обрыв.
+    // Для продолженной сессии возвращаем ее комнату и пропущенные фреймы.
+    //
+    // Клиент может вернуться раньше, чем мы заметим, что старый сокет мертв. С верным
+    // токеном сессию все равно продолжаем, а старый хэндлер просим закрыть соединение
+    pub fn add_peer(
+        &mut self,
+        peer: Peer,
+        resume_token: Option<uuid::Uuid>,
+    ) -> Option<(String, Vec<protocol::PupaFrame>)> {
+        let Some(previous_peer_record) = self.peers.get_mut(&peer.player_id) else {
+            self.peers.insert(peer.player_id, peer);
+            return None;
+        };
+
+        let resumed = resume_token.is_some()
+            && resume_token == Some(previous_peer_record.resume_token)
+            && (previous_peer_record.online || previous_peer_record.is_resumable());
+        let missed = std::mem::take(&mut previous_peer_record.missed);
+
+        if resumed && previous_peer_record.online {
+            if let Some(channel) = &previous_peer_record.channel {
+                let _ = channel.try_send(protocol::PupaFrame::Kicked {
+                    reason: "session is resumed from another connection".to_string(),
+                });
+            }
+        }
+
+        previous_peer_record.online = true;
+        previous_peer_record.channel = peer.channel;
+        previous_peer_record.address = peer.address;
+        previous_peer_record.resume_token = peer.resume_token;
+        previous_peer_record.resumable_until = None;
+        // Без имени в Authorize оставляем то, что было
+        if peer.name.is_some() {
+            previous_peer_record.name = peer.name;
+        }
+
+        if resumed {
+            Some((previous_peer_record.room.clone(), missed.into()))
+        } else {
+            previous_peer_record.room = peer.room;
+            None
+        }
+    }
+
+    // Когда комнат уже max_rooms, сначала забываем пустые комнаты без таблицы, и
+    // отказываем, только если это не помогло. Возвращаем забытые комнаты, чтобы
+    // хэндлер убрал и их сторы сообщений
+    pub fn join_room(
+        &mut self,
+        player_id: uuid::Uuid,
+        room: &str,
+        max_rooms: usize,
+    ) -> Result<Vec<String>, protocol::ErrorReason> {
+        let mut dropped = Vec::new();
+        if !self.rooms.contains_key(room) {
+            if self.rooms.len() >= max_rooms {
+                dropped = self.drop_idle_rooms();
+            }
+            if self.rooms.len() >= max_rooms {
+                return Err(protocol::ErrorReason::TooManyRooms);
+            }
+            self.rooms.insert(room.to_string(), HashMap::new());
+        }
+
+        if let Some(peer) = self.peers.get_mut(&player_id) {
+            peer.room = room.to_string();
+        }
+        Ok(dropped)
+    }
+
+    // Комнаты, в которых никто не играет и которые никто не выигрывал, кроме комнаты по умолчанию.
+    // Игрока, который может продолжить сессию, считаем все еще сидящим в своей комнате
+    fn drop_idle_rooms(&mut self) -> Vec<String> {
+        let occupied = self
+            .peers
+            .values()
+            .filter(|peer| peer.online || peer.is_resumable())
+            .map(|peer| peer.room.as_str())
+            .collect::<std::collections::HashSet<_>>();
+        let idle = self
+            .rooms
+            .iter()
+            .filter(|(room, standings)| {
+                room.as_str() != protocol::DEFAULT_ROOM
+                    && standings.is_empty()
+                    && !occupied.contains(room.as_str())
+            })
+            .map(|(room, _)| room.clone())
+            .collect::<Vec<_>>();
+
+        for room in idle.iter() {
+            self.rooms.remove(room);
+            self.streaks.remove(room);
+        }
+        idle
+    }
+
+    // Все известные комнаты с количеством игроков онлайн в каждой
+    pub fn list_rooms(&self) -> Vec<protocol::RoomInfo> {
+        let mut rooms = self
+            .rooms
+            .keys()
+            .map(|room| protocol::RoomInfo {
+                name: room.clone(),
+                players: self
+                    .peers
+                    .values()
+                    .filter(|peer| peer.online && &peer.room == room)
+                    .count() as u32,
+            })
+            .collect::<Vec<_>>();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        rooms
+    }
+
+    // Таблица комнаты: публичный id, сидит ли игрок в комнате прямо сейчас и его результаты
+    pub fn get_room_winners(&self, room: &str) -> Vec<(uuid::Uuid, bool, RoomStanding)> {
+        let Some(standings) = self.rooms.get(room) else {
+            return Vec::new();
+        };
+
+        let mut winners = standings
+            .iter()
+            .map(|(player_id, standing)| {
+                let in_room = self
+                    .peers
+                    .get(player_id)
+                    .map(|peer| peer.online && peer.room == room)
+                    .unwrap_or(false);
+                (*player_id, in_room, *standing)
+            })
+            .collect::<Vec<_>>();
+        winners.sort_by(|a, b| {
+            b.2.score
+                .cmp(&a.2.score)
+                .then_with(|| b.2.wins.cmp(&a.2.wins))
+        });
+        winners
+    }
+
+    // Мы просто отключим пира от канала для общения с его хэндлером
+    // и поменяем статус на offline, а так пусть лежит в общей хэшмапе.
+    // Если игрок уже переподключился, то канал у него новый, и новую сессию не трогаем
+    pub fn disable_peer(
+        &mut self,
+        player_id: uuid::Uuid,
+        channel: &tokio::sync::mpsc::Sender<protocol::PupaFrame>,
+    ) {
+        if let Some(active_peer) = self.peers.get_mut(&player_id) {
+            if !active_peer
+                .channel
+                .as_ref()
+                .is_some_and(|active| active.same_channel(channel))
+            {
+                return;
+            }
+            active_peer.go_offline(self.resume_grace);
+        }
+    }
+
+    pub fn is_banned(&self, player_id: uuid::Uuid) -> bool {
+        self.peers
+            .get(&player_id)
+            .map(|peer| peer.banned)
+            .unwrap_or(false)
+    }
+
+    // Штраф снимает победу и в общей таблице, и в таблице комнаты, где был ранний Flash,
+    // иначе по очкам оштрафованный игрок оставался бы на своем месте
+    pub fn record_early_flash(
+        &mut self,
+        player_id: uuid::Uuid,
+        config: &Config,
+    ) -> EarlyFlashVerdict {
+        let Some(peer) = self.peers.get_mut(&player_id) else {
+            return EarlyFlashVerdict::Warned;
+        };
+
+        peer.early_flashes += 1;
+
+        if config
+            .early_flash_ban_after
+            .is_some_and(|limit| peer.early_flashes >= limit)
+        {
+            peer.banned = true;
+            EarlyFlashVerdict::Banned
+        } else if config
+            .early_flash_penalty_after
+            .is_some_and(|limit| peer.early_flashes >= limit)
+        {
+            take_win(&mut peer.wins, &mut peer.score);
+            if let Some(standing) = self
+                .rooms
+                .get_mut(&peer.room)
+                .and_then(|standings| standings.get_mut(&player_id))
+            {
+                take_win(&mut standing.wins, &mut standing.score);
+            }
+            EarlyFlashVerdict::Penalised
+        } else {
+            EarlyFlashVerdict::Warned
+        }
+    }
+
+    // Продлеваем серию победителя в комнате или начинаем новую, возвращаем ее длину
+    pub fn record_streak(&mut self, room: &str, player_id: uuid::Uuid) -> u32 {
+        let streak = self
+            .streaks
+            .entry(room.to_string())
+            .or_insert((player_id, 0));
+        if streak.0 != player_id {
+            *streak = (player_id, 0);
+        }
+        streak.1 += 1;
+        streak.1
+    }
+
+    // Победитель выиграл партию у каждого, кому было доставлено сообщение
+    pub fn update_ratings(
+        &mut self,
+        elo: &crate::scoring::Elo,
+        winner: uuid::Uuid,
+        recipients: &std::collections::HashSet<uuid::Uuid>,
+    ) {
+        let Some(winner_rating) = self.peers.get(&winner).map(|peer| peer.rating) else {
+            return;
+        };
+        let losers = recipients
+            .iter()
+            .filter(|player_id| **player_id != winner)
+            .filter_map(|player_id| {
+                self.peers
+                    .get(player_id)
+                    .map(|peer| (peer.player_id, peer.rating))
+            })
+            .collect::<Vec<_>>();
+
+        let (winner_rating, loser_ratings) = elo.update(
+            winner_rating,
+            &losers.iter().map(|(_, rating)| *rating).collect::<Vec<_>>(),
+        );
+
+        if let Some(peer) = self.peers.get_mut(&winner) {
+            peer.rating = winner_rating;
+        }
+        for ((player_id, _), rating) in losers.iter().zip(loser_ratings) {
+            if let Some(peer) = self.peers.get_mut(player_id) {
+                peer.rating = rating;
+            }
+        }
+    }
+
+    // Нарушения считаем в окне rate_limit_window: редкие упоры в лимит прощаются,
+    // а тот, кто долбит сервер постоянно, будет отключен
+    fn check_rate(
+        &mut self,
+        player_id: uuid::Uuid,
+        frame: LimitedFrame,
+        config: &Config,
+    ) -> RateVerdict {
+        let Some(peer) = self.peers.get_mut(&player_id) else {
+            return RateVerdict::Allowed;
+        };
+        let limits = &mut peer.limits;
+        let now = std::time::Instant::now();
+
+        let bucket = match frame {
+            LimitedFrame::Content => limits.content.as_mut(),
+            LimitedFrame::Flash => limits.flash.as_mut(),
+        };
+        if bucket.is_none_or(|bucket| bucket.try_take(now)) {
+            return RateVerdict::Allowed;
+        }
+
+        if limits.violations == 0
+            || now.saturating_duration_since(limits.violations_since) > config.rate_limit_window
+        {
+            limits.violations = 0;
+            limits.violations_since = now;
+        }
+        limits.violations += 1;
+
+        if config
+            .rate_limit_disconnect_after
+            .is_some_and(|limit| limits.violations >= limit)
+        {
+            RateVerdict::Disconnect
+        } else {
+            RateVerdict::Limited
+        }
+    }
+
+    // Лимиты поменялись, раздаем всем новые полные ведра
+    fn reset_limits(&mut self, config: &Config) {
+        for peer in self.peers.values_mut() {
+            peer.limits = PlayerLimits::new(config);
+        }
+        self.register_limits.clear();
+    }
+
+    // Можно ли с этого адреса зарегистрировать еще одного игрока
+    fn check_register_rate(&mut self, ip: std::net::IpAddr, config: &Config) -> bool {
+        let Some(limit) = config.register_limit else {
+            return true;
+        };
+        let now = std::time::Instant::now();
+
+        // Полное ведро ничем не отличается от нового, такие адреса можно забыть
+        if self.register_limits.len() >= 10_000 {
+            self.register_limits
+                .retain(|_, bucket| !bucket.is_full(now));
+        }
+
+        self.register_limits
+            .entry(ip.to_canonical())
+            .or_insert_with(|| TokenBucket::new(limit, now))
+            .try_take(now)
+    }
+
+    // Обнуляем всю живую статистику. В отличие от конца сезона, прощаем и нарушения
+    pub fn reset_stats(&mut self) {
+        for peer in self.peers.values_mut() {
+            peer.messages_received = 0;
+            peer.messages_sent = 0;
+            peer.wins = 0;
+            peer.score = 0;
+            peer.rating = crate::scoring::INITIAL_RATING;
+            peer.win_latencies = LatencyStats::new();
+            peer.early_flashes = 0;
+            peer.invalid_content = 0;
+        }
+        for standings in self.rooms.values_mut() {
+            standings.clear();
+        }
+        self.streaks.clear();
+    }
+
+    // Бан или разбан уже известного серверу игрока. Возвращаем его подпись,
+    // чтобы записать бан в списки доступа, или None, если такого игрока мы не видели
+    pub fn set_banned(&mut self, player_id: uuid::Uuid, banned: bool) -> Option<uuid::Uuid> {
+        let peer = self.peers.get_mut(&player_id)?;
+        peer.banned = banned;
+        Some(peer.signature)
+    }
+
+    // Просим хэндлер игрока закрыть соединение. false, если игрок не онлайн.
+    // Игрока сразу отцепляем и не ждем хэндлер: если тот не разбирает свой канал,
+    // то соединение закроет таймаут записи или heartbeat.
+    //
+    // Сессию выгнанного игрока заканчиваем совсем. Иначе клиент переподключился бы
+    // по resume_token в ту же секунду, и кик ничего бы не значил
+    fn kick(&mut self, player_id: uuid::Uuid, reason: &str) -> bool {
+        let Some(peer) = self.peers.get_mut(&player_id) else {
+            return false;
+        };
+        peer.resume_token = uuid::Uuid::new_v4();
+        peer.resumable_until = None;
+        peer.missed.clear();
+        let Some(channel) = &peer.channel else {
+            return false;
+        };
+
+        match channel.try_send(protocol::PupaFrame::Kicked {
+            reason: reason.to_string(),
+        }) {
+            Ok(()) | Err(tokio::sync::mpsc::error::TrySendError::Full(_)) => {
+                peer.go_offline(std::time::Duration::ZERO);
+                true
+            }
+            Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => false,
+        }
+    }
+
+    // Профиль лежит в Peer вместе со статистикой, сбросы статистики и сезоны его не трогают
+    pub fn set_profile(
+        &mut self,
+        player_id: uuid::Uuid,
+        name: Option<String>,
+        avatar: Option<String>,
+    ) -> Option<protocol::PupaFrame> {
+        let peer = self.peers.get_mut(&player_id)?;
+        peer.name = name.clone();
+        peer.avatar = avatar.clone();
+        let signature = peer.signature;
+        self.players
+            .set_profile(signature, crate::players::Profile { name, avatar });
+        self.profile(player_id)
+    }
+
+    // Игрок, который после перезапуска еще не заходил, есть только в реестре
+    pub fn profile(&self, player_id: uuid::Uuid) -> Option<protocol::PupaFrame> {
+        let (name, avatar) = match self.peers.get(&player_id) {
+            Some(peer) => (peer.name.clone(), peer.avatar.clone()),
+            None => {
+                let profile = self.players.profile_of(player_id)?;
+                (profile.name.clone(), profile.avatar.clone())
+            }
+        };
+        Some(protocol::PupaFrame::Profile {
+            player_id,
+            name,
+            avatar,
+        })
+    }
+
+    pub fn record_rtt(&mut self, player_id: uuid::Uuid, rtt_ms: u64) {
+        if let Some(peer) = self.peers.get_mut(&player_id) {
+            peer.rtt.record(rtt_ms);
+        }
+    }
+
+    pub fn record_invalid_content(&mut self, player_id: uuid::Uuid) {
+        if let Some(peer) = self.peers.get_mut(&player_id) {
+            peer.invalid_content += 1;
+        }
+    }
+
+    pub fn update_winners(
+        &mut self,
+        player_id: uuid::Uuid,
+        room: &str,
+        latency_ms: u64,
+        points: u32,
+    ) {
+        if let Some(active_peer) = self.peers.get_mut(&player_id) {
+            active_peer.wins += 1;
+            active_peer.score += points as u64;
+            active_peer.win_latencies.record(latency_ms);
+        }
+
+        let standing = self
+            .rooms
+            .entry(room.to_string())
+            .or_default()
+            .entry(player_id)
+            .or_default();
+        standing.wins += 1;
+        standing.score += points as u64;
+    }
+
+    // Отправить фрейм конкретному игроку через канал его хэндлера, если он сейчас онлайн,
+    // или отложить до переподключения, если его сессию еще можно продолжить
+    fn send_to(&mut self, player_id: uuid::Uuid, frame: protocol::PupaFrame) {
+        if let Some(peer) = self.peers.get_mut(&player_id) {
+            peer.deliver(frame, self.resume_grace);
+        }
+    }
+
+    // Не хочется менять Хэшмапу, потому что мы работаем с пользователем по ключу,
+    // поэтому аллоцируем здоровый массив и сортируем его. Не очень быстро, но будем
+    // надеятся, что не так часто сюда заходит клиент
+    //
+    // Сортируем по рейтингу, если он включен, иначе по очкам. Победы остаются только
+    // для равных, иначе таблица снова показывала бы, кто дольше всех подключен
+    pub fn get_sorted_winners(&self, by_rating: bool) -> Vec<Peer> {
+        let mut peers = self.peers.values().cloned().collect::<Vec<Peer>>();
+        peers.sort_by(|a, b| {
+            b.online
+                .cmp(&a.online)
+                .then_with(|| {
+                    if by_rating {
+                        b.rating.total_cmp(&a.rating)
+                    } else {
+                        std::cmp::Ordering::Equal
+                    }
+                })
+                .then_with(|| b.score.cmp(&a.score))
+                .then_with(|| b.wins.cmp(&a.wins))
+        });
+        peers
+    }
+
+    // Игроки, у которых уже есть хотя бы одна победа, от самых быстрых по медиане к медленным
+    pub fn get_latency_leaderboard(&self) -> Vec<(uuid::Uuid, crate::latency::LatencySummary)> {
+        let mut leaderboard = self
+            .peers
+            .values()
+            .filter_map(|peer| {
+                peer.win_latencies
+                    .summary()
+                    .map(|summary| (peer.player_id, summary))
+            })
+            .collect::<Vec<_>>();
+        leaderboard.sort_by_key(|(_, summary)| (summary.median_ms, summary.min_ms));
+        leaderboard
+    }
+
+    // Когда я удаляю сообщения из MessageStore я не обновляю счетчики здесь,
+    // то есть для меня это исторические счетчики и я никак не связываю их с сообщениями.
+    //
+    // Возвращаем список тех, кому сообщение реально ушло в канал, только они потом
+    // смогут претендовать на него через Flash. Рассылаем только внутри комнаты.
+    // Тем, кто только что отвалился и еще может продолжить сессию, сообщение
+    // откладываем в буфер, и побороться за него они смогут после переподключения.
+    fn broadcast(
+        &mut self,
+        sender: uuid::Uuid,
+        room: &str,
+        message: protocol::PupaFrame,
+        metrics: &Metrics,
+    ) -> Vec<uuid::Uuid> {
+        let mut recipients = Vec::new();
+
+        // Обновим счетчик отправленых для sender
+        if let Some(current_peer) = self.peers.get_mut(&sender) {
+            current_peer.messages_sent += 1;
+        }
+
+        for peer in self.peers.iter_mut() {
+            if *peer.0 != sender && (peer.1.online || peer.1.is_resumable()) && peer.1.room == room
+            {
+                log::debug!(
+                    "From {} Sending to {}, msg: {:?}",
+                    sender,
+                    peer.1.player_id,
+                    message
+                );
+
+                // TODO: Тут нужно подрефакторить clone(), если хватит времени
+                let sent = peer.1.deliver(message.clone(), self.resume_grace);
+
+                // Канал закрыт, если хэндлер пира уже завершился, а disable_peer еще не успел отработать
+                if sent {
+                    metrics.broadcasts_delivered.inc();
+                    recipients.push(*peer.0);
+                } else {
+                    metrics.broadcasts_dropped.inc();
+                }
+
+                peer.1.messages_received += 1;
+            }
+        }
+
+        recipients
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Peer {
+    player_id: uuid::Uuid,
+    name: Option<String>,
+    avatar: Option<String>,
+    // Подпись нужна только для проверки по спискам доступа и админке
+    signature: uuid::Uuid,
+    online: bool,
+    messages_received: u32,
+    messages_sent: u32,
+    wins: u32,
+    score: u64,
+    rating: f64,
+    win_latencies: LatencyStats,
+    // Время Ping/Pong, это про сеть игрока, поэтому сбросы статистики его не трогают
+    rtt: LatencyStats,
+    early_flashes: u32,
+    invalid_content: u32,
+    banned: bool,
+    limits: PlayerLimits,
+    room: String,
+    // Адрес последнего подключения, нужен только админке
+    address: std::net::SocketAddr,
+    channel: Option<tokio::sync::mpsc::Sender<protocol::PupaFrame>>,
+    // Токен текущей сессии. Пока идет окно resumable_until после обрыва, фреймы
+    // игроку складываются в missed и отдаются ему при переподключении с этим токеном
+    resume_token: uuid::Uuid,
+    resumable_until: Option<std::time::Instant>,
+    missed: std::collections::VecDeque<protocol::PupaFrame>,
+}
+
+// Больше этого за время обрыва не копим, самые старые фреймы выкидываем
+const MAX_MISSED_FRAMES: usize = 100;
+
+impl Peer {
+    fn is_resumable(&self) -> bool {
+        !self.online
+            && self
+                .resumable_until
+                .is_some_and(|until| std::time::Instant::now() < until)
+    }
+
+    // Игрок отвалился: отцепляем канал хэндлера и держим сессию resume_grace
+    fn go_offline(&mut self, resume_grace: std::time::Duration) {
+        self.online = false;
+        self.channel = None;
+        self.resumable_until = Some(std::time::Instant::now() + resume_grace);
+    }
+
+    // Онлайн отправляем в канал хэндлера, в окне переподключения копим в буфер.
+    // false, если фрейм никуда не попал.
+    //
+    // Ждать место в канале нельзя, мы под локом State, и один полуоткрытый игрок
+    // остановил бы весь сервер. Если канал забит, хэндлер не успевает писать в сокет,
+    // так что считаем игрока отвалившимся, а фреймы дальше копим до переподключения
+    fn deliver(&mut self, frame: protocol::PupaFrame, resume_grace: std::time::Duration) -> bool {
+        let frame = match &self.channel {
+            Some(channel) => match channel.try_send(frame) {
+                Ok(()) => return true,
+                Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => return false,
+                Err(tokio::sync::mpsc::error::TrySendError::Full(frame)) => {
+                    log::info!(
+                        "User {} is not reading frames, taking it offline",
+                        self.player_id
+                    );
+                    self.go_offline(resume_grace);
+                    frame
+                }
+            },
+            None => frame,
+        };
+        if !self.is_resumable() {
+            return false;
+        }
+
+        if self.missed.len() == MAX_MISSED_FRAMES {
+            self.missed.pop_front();
+        }
+        self.missed.push_back(frame);
+        true
+    }
+}
+
+// Поднимаем игровой сервер, API и метрики и обслуживаем их, пока процесс жив
+pub async fn run() {
+    let game_server_port = env::var("GAME_SERVER_PORT")
+        .expect("GAME_SERVER_PORT environment variable not set")
+        .parse::<u32>()
+        .expect("GAME_SERVER_PORT  environment variable is not a valid number");
+    let api_server_port = env::var("API_SERVER_PORT")
+        .expect("API_SERVER_PORT environment variable not set")
+        .parse::<u32>()
+        .expect("API_SERVER_PORT  environment variable is not a valid number");
+
+    // Этот сервер обрабатывает логику игры (общение с клиентами сообщения)
+    let game_server_listener =
+        tokio::net::TcpListener::bind(format!("127.0.0.1:{}", game_server_port))
+            .await
+            .expect("Cannot bind listener to the port provided");
+    log::debug!("Started a game server at 127.0.0.1:{}", game_server_port);
+
+    // Этот сервер обрабатывает АПИ запросы для статистики и так далее
+    let api_server_listener =
+        tokio::net::TcpListener::bind(format!("127.0.0.1:{}", api_server_port))
+            .await
+            .expect("Cannot bind api_server_listener to the port provided");
+    log::debug!("Started an API server at 127.0.0.1:{}", api_server_port);
+
+    let metrics = Arc::new(Metrics::new());
+
+    // Метрики опциональны, если порт не задан, то и HTTP сервер для них не поднимаем
+    if let Ok(metrics_server_port) = env::var("METRICS_SERVER_PORT") {
+        let metrics_server_port = metrics_server_port
+            .parse::<u32>()
+            .expect("METRICS_SERVER_PORT  environment variable is not a valid number");
+        let metrics_server_listener =
+            tokio::net::TcpListener::bind(format!("127.0.0.1:{}", metrics_server_port))
+                .await
+                .expect("Cannot bind metrics_server_listener to the port provided");
+        log::debug!(
+            "Started a metrics server at 127.0.0.1:{}/metrics",
+            metrics_server_port
+        );
+
+        tokio::spawn(crate::metrics::serve(
+            metrics_server_listener,
+            Arc::clone(&metrics),
+        ));
+    }
+
+    let config = Config::from_env();
+    let access = match &config.access_list_file {
+        Some(path) => AccessList::load(path).unwrap_or_else(|e| panic!("{}", e)),
+        None => AccessList::new(),
+    };
+    let players = match &config.players_file {
+        Some(path) => PlayerRegistry::open(path).unwrap_or_else(|e| panic!("{}", e)),
+        None => PlayerRegistry::new(),
+    };
+    log::debug!("{} players are known", players.len());
+    if config.require_registration && config.players_file.is_none() {
+        log::warn!("REQUIRE_REGISTRATION without PLAYERS_FILE, registrations are lost on restart");
+    }
+
+    let rules = rules_from_env();
+    log::debug!("Playing by the {} rules", rules.name());
+
+    let ctx = Arc::new(Context {
+        state: Mutex::new(State::new(players, config.resume_grace)),
+        message_stores: Mutex::new(MessageStores::default()),
+        winlog_store: Mutex::new(WinLogStore::new()),
+        metrics,
+        access: std::sync::RwLock::new(access),
+        access_file: Mutex::new(()),
+        api_keys: api_keys_from_env(),
+        config: std::sync::RwLock::new(config),
+        rules,
+        scoring: scoring_from_env(),
+    });
+    let game_ctx = Arc::clone(&ctx);
+
+    // По SIGHUP перечитываем списки доступа, как это принято у демонов
+    #[cfg(unix)]
+    if ctx.config().access_list_file.is_some() {
+        tokio::spawn(reload_access_list_on_sighup(Arc::clone(&ctx)));
+    }
+
+    // Таймер сезонов, если они включены
+    let round_duration = ctx.config().round_duration;
+    if let Some(round_duration) = round_duration {
+        tokio::spawn(run_rounds(Arc::clone(&ctx), round_duration));
+    }
+
+    // Запустим пару серверов на одном рантайме. Конечно с внешним хранилищем можно было бы разделить их на разные процессы.
+    // Наверное тут можно было бы и на разных рантаймах запустить, чтобы мы могли их workloadы изолировать, но пусть в первой версии так побудут
+    let _ = tokio::try_join!(
+        tokio::spawn(async move {
+            loop {
+                let ctx = Arc::clone(&game_ctx);
+
+                // В peer хранится ip адрес и порт входящего подключения.
+                let (socket, peer) = game_server_listener.accept().await.unwrap();
+
+                // Для каждого входящего подключения мы будем создавать отдельную задачу.
+                tokio::spawn(async move {
+                    run_game_handler(socket, peer, ctx).await;
+                });
+            }
+        }),
+        tokio::spawn(async move {
+            loop {
+                let ctx = Arc::clone(&ctx);
+
+                // В peer хранится ip адрес и порт входящего подключения.
+                let (socket, peer) = api_server_listener.accept().await.unwrap();
+
+                tokio::spawn(async move {
+                    run_api_handler(socket, peer, ctx).await;
+                });
+            }
+        })
+    );
+}
+
+async fn run_game_handler(
+    socket: tokio::net::TcpStream,
+    peer: std::net::SocketAddr,
+    ctx: Arc<Context>,
+) {
+    log::debug!(
+        "New Game server connection from {}:{}",
+        peer.ip(),
+        peer.port()
+    );
+
+    let codec = protocol::PupaCodec::new();
+    let (read_half, write_half) = socket.into_split();
+
+    // Дуплексный канал для общения с клиентом по TCP
+    let mut reader = tokio_util::codec::FramedRead::new(read_half, codec.clone());
+    let mut writer = PeerWriter {
+        inner: tokio_util::codec::FramedWrite::new(write_half, codec),
+        timeout: ctx.config().heartbeat_timeout,
+        failed: false,
+    };
+
+    // Внутренний канал, для обратной связи с модулем
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<protocol::PupaFrame>(10);
+    let current_player: uuid::Uuid;
+    let resumed: Option<(String, Vec<protocol::PupaFrame>)>;
+    // Копия комнаты из State, чтобы не ходить за ней под lock на каждом фрейме
+    let mut current_room = protocol::DEFAULT_ROOM.to_string();
+
+    // Возможные вопросы по авторизации:
+    // А что если клиент подключиться и не будет использовать подключение?
+    // Я решил таймауты никуда не добавлять, но можно сделать какой-нибудь inactive_timeout, чтобы резать такие подключения.
+    //
+    // Получается, что все равно клиент может подключиться без авторизации и только после того, как пошлет первый фрейм он получит AuthError?
+    // Да. Так как клиент получает клиентскую библиотеку для работы, то она инкапсулирует правильное поведение.
+    // Если клиент пошлет плохой фрейм, мы отдаем Unauthorized, если клиент просто висит долго, то сейчас я ничего не делаю, но можно добавить таймаут.
+    //
+    // Игрок либо приходит со своей подписью, либо регистрируется, и тогда подпись ему выдаем мы
+    let (signature, name, resume_token, registering) = match reader.next().await {
+        Some(Ok(protocol::PupaFrame::Authorize {
+            signature,
+            name,
+            resume_token,
+        })) => (signature, name, resume_token, false),
+        Some(Ok(protocol::PupaFrame::Register { name })) => {
+            (uuid::Uuid::new_v4(), name, None, true)
+        }
+        Some(Ok(_)) => {
+            log::debug!(
+                "Incorrect Authorization Frame | peer rejected [{}:{}]",
+                peer.ip(),
+                peer.port()
+            );
+            let _ = writer.send(protocol::PupaFrame::NonAuthorized).await;
+            return;
+        }
+        Some(Err(_)) => {
+            ctx.metrics.decode_errors.inc();
+            log::debug!(
+                "Malformed Authorization Frame | peer rejected [{}:{}]",
+                peer.ip(),
+                peer.port()
+            );
+            let _ = writer.send(protocol::PupaFrame::NonAuthorized).await;
+            return;
+        }
+        None => {
+            log::debug!(
+                "Socket disconneted on Authorization | peer rejected [{}:{}]",
+                peer.ip(),
+                peer.port()
+            );
+            return;
+        }
+    };
+
+    if registering
+        && !ctx
+            .state()
+            .await
+            .check_register_rate(peer.ip(), &ctx.config())
+    {
+        log::debug!(
+            "Register is rate limited | peer rejected [{}:{}]",
+            peer.ip(),
+            peer.port()
+        );
+        ctx.metrics.rate_limited.inc();
+        let _ = writer
+            .send(protocol::PupaFrame::Error {
+                msg_id: None,
+                reason: protocol::ErrorReason::RateLimited,
+            })
+            .await;
+        return;
+    }
+
+    log::debug!("Authorizing peer [{}:{}]", peer.ip(), peer.port());
+    if name
+        .as_deref()
+        .is_some_and(|name| !protocol::is_valid_display_name(name))
+    {
+        let _ = writer
+            .send(protocol::PupaFrame::Error {
+                msg_id: None,
+                reason: protocol::ErrorReason::InvalidDisplayName,
+            })
+            .await;
+        return;
+    }
+
+    // Окей, мы прошли авторизацию, можно добавить пользователя в наш список
+    let mut new_peer = Peer {
+        player_id: uuid::Uuid::nil(),
+        name,
+        avatar: None,
+        signature,
+        online: true,
+        messages_received: 0,
+        messages_sent: 0,
+        wins: 0,
+        score: 0,
+        rating: crate::scoring::INITIAL_RATING,
+        win_latencies: LatencyStats::new(),
+        rtt: LatencyStats::new(),
+        early_flashes: 0,
+        invalid_content: 0,
+        banned: false,
+        limits: PlayerLimits::new(&ctx.config()),
+        room: protocol::DEFAULT_ROOM.to_string(),
+        address: peer,
+        channel: Some(tx.clone()),
+        resume_token: uuid::Uuid::new_v4(),
+        resumable_until: None,
+        missed: std::collections::VecDeque::new(),
+    };
+    let session_token = new_peer.resume_token;
+
+    {
+        let mut state = ctx.state().await;
+        // Если регистрация обязательна, незнакомую подпись не пускаем
+        if !registering && ctx.config().require_registration && !state.is_registered(signature) {
+            drop(state);
+            log::debug!(
+                "Unregistered signature | peer rejected [{}:{}]",
+                peer.ip(),
+                peer.port()
+            );
+            let _ = writer.send(protocol::PupaFrame::NonAuthorized).await;
+            return;
+        }
+
+        let known_player = state.player_id(signature);
+        let access = if known_player.is_some_and(|player_id| state.is_banned(player_id)) {
+            Err(protocol::AccessDenied::SignatureBanned)
+        } else {
+            ctx.access.read().unwrap().check(signature, peer.ip())
+        };
+        if let Err(reason) = access {
+            drop(state);
+            log::debug!(
+                "Banned player {:?} | peer rejected [{}:{}] | {:?}",
+                known_player,
+                peer.ip(),
+                peer.port(),
+                reason
+            );
+            let _ = writer.send(protocol::PupaFrame::Banned { reason }).await;
+            return;
+        }
+        state.admit(&mut new_peer);
+        current_player = new_peer.player_id;
+        resumed = state.add_peer(new_peer, resume_token);
+    }
+    ctx.metrics.connected_peers.inc();
+
+    let frame = if registering {
+        log::info!("User {} is registered", current_player);
+        protocol::PupaFrame::Registered {
+            signature,
+            player_id: current_player,
+            resume_token: session_token,
+        }
+    } else {
+        protocol::PupaFrame::Authorized {
+            player_id: current_player,
+            resume_token: session_token,
+            resumed: resumed.is_some(),
+        }
+    };
+    let _ = writer.send(frame).await;
+
+    // Сессия продолжилась: возвращаемся в свою комнату и досылаем все, что игрок пропустил
+    if let Some((room, missed)) = resumed {
+        log::info!(
+            "User {} resumed the session in room {} with {} missed frames",
+            current_player,
+            room,
+            missed.len()
+        );
+        current_room = room;
+        for frame in missed {
+            let _ = writer.send(frame).await;
+        }
+    }
+
+    // Сразу расскажем новому игроку, какой сейчас сезон и сколько ему осталось
+    let round_duration = ctx.config().round_duration;
+    if let Some(round_duration) = round_duration {
+        let frame = ctx.state().await.round_started_frame(round_duration);
+        let _ = writer.send(frame).await;
+    }
+
+    // Время жизни сессии, от него считаем nonce для Ping
+    let session_started = std::time::Instant::now();
+    let mut last_seen = session_started;
+    // Последний Ping без ответа и когда он ушел. Время туда и обратно считаем по своим часам,
+    // а Pong с чужим nonce игнорируем, иначе клиент мог бы нарисовать себе любой rtt_ms
+    let mut outstanding_ping: Option<(u64, std::time::Instant)> = None;
+    let mut heartbeat = ctx
+        .config()
+        .heartbeat_interval
+        .map(|interval| tokio::time::interval_at(tokio::time::Instant::now() + interval, interval));
+
+    loop {
+        if writer.failed {
+            log::info!(
+                "User {} is not accepting frames, disconnecting",
+                current_player
+            );
+            break;
+        }
+
+        tokio::select! {
+            // Пингуем игрока. Если он давно ничего не присылал, даже Pong, то соединение
+            // скорее всего полуоткрыто, и держать игрока онлайн дальше нет смысла
+            _ = next_heartbeat(&mut heartbeat) => {
+                if last_seen.elapsed() > ctx.config().heartbeat_timeout {
+                    log::info!("User {} is not responding, disconnecting", current_player);
+                    ctx.metrics.heartbeat_timeouts.inc();
+                    break;
+                }
+                let nonce = session_started.elapsed().as_micros() as u64;
+                outstanding_ping = Some((nonce, std::time::Instant::now()));
+                let _ = writer.send(protocol::PupaFrame::Ping { nonce }).await;
+            }
+            // Обработчик broadcast сообщений,
+            // это до нас долетело чужое Content сообщение
+            // Запишем его клиенту и пусть он готовит Flash
+            Some(msg) = rx.recv() => {
+                // Kicked приходит от админки, отдаем его клиенту и закрываем соединение
+                let kicked = matches!(msg, protocol::PupaFrame::Kicked { .. });
+                let _ = writer.send(msg).await;
+                if kicked {
+                    log::info!("User {} is kicked", current_player);
+                    break;
+                }
+            }
+            // Здесь мы просто обрабатываем сокет от клиента
+            // все, что он нам пишет приходит сюда
+            result = reader.next() => match result.inspect(|_| last_seen = std::time::Instant::now()) {
+            Some(Ok(frame)) => match frame {
+                // Если мы получили контент от клиента, то нам нужно разослать его всем активным
+                // клиентам + сохранить сообщение в нашу коллекцию 500 последних сообщений
+                protocol::PupaFrame::Content { msg_id, body } => {
+                    log::debug!(
+                        "Content | msg_id: {}, body: {:?} for [{}:{}] ",
+                        msg_id,
+                        body,
+                        peer.ip(),
+                        peer.port()
+                    );
+
+                    ctx.metrics.content_received.inc();
+
+                    match check_rate(&ctx, &mut writer, current_player, msg_id, LimitedFrame::Content).await {
+                        RateVerdict::Allowed => {}
+                        RateVerdict::Limited => continue,
+                        RateVerdict::Disconnect => break,
+                    }
+
+                    // Сначала общая политика сервера, потом то, что хотят правила игры.
+                    // Нарушение любой из них считается невалидным Content
+                    let validation = ctx
+                        .config()
+                        .content_policy
+                        .validate(&body)
+                        .and_then(|()| ctx.rules.validate_content(msg_id, &body));
+                    if let Err(reason) = validation {
+                        log::debug!("Content rejected | msg_id: {}, reason: {:?} for [{}:{}]", msg_id, reason, peer.ip(), peer.port());
+                        ctx.state().await.record_invalid_content(current_player);
+                        let _ = writer.send(protocol::PupaFrame::Error { msg_id: Some(msg_id), reason }).await;
+                        continue;
+                    }
+
+                    let reserved = ctx.message_stores().await.reserve(msg_id);
+                    if !reserved {
+                        log::debug!("Content rejected | msg_id: {} is reused by [{}:{}]", msg_id, peer.ip(), peer.port());
+                        let _ = writer.send(protocol::PupaFrame::Error { msg_id: Some(msg_id), reason: protocol::ErrorReason::DuplicateMessage }).await;
+                        continue;
+                    }
+
+                    let broadcast_at = std::time::Instant::now();
+
+                    // Броадкастим на всех клиентов
+                    let recipients = ctx
+                        .state()
+                        .await
+                        .broadcast(current_player, &current_room, protocol::PupaFrame::Content { msg_id, body: body.clone() }, &ctx.metrics);
+
+                    // Добавляем в список сообщений уже после броадкаста, когда известно, кому оно
+                    // доставлено. Если кто-то успеет прислать Flash раньше, чем мы сюда дойдем,
+                    // то для него сообщения просто еще не существует.
+                    //
+                    // Резерв снимаем в любом случае: дальше повторы отсекает уже сам стор
+                    {
+                        let mut message_stores = ctx.message_stores().await;
+                        message_stores.release(msg_id);
+                        let inserted = message_stores.rooms.entry(current_room.clone()).or_insert_with(|| MessageStore::with_ttl(ctx.config().message_ttl)).insert(StoredMessage::new(
+                            msg_id,
+                            current_player,
+                            body,
+                            broadcast_at,
+                            recipients.into_iter().collect(),
+                        ));
+                        ctx.update_message_store_size(&message_stores);
+
+                        if let Err(reason) = inserted {
+                            log::warn!("Content | msg_id: {} was broadcast twice by [{}:{}]", msg_id, peer.ip(), peer.port());
+                            let _ = writer.send(protocol::PupaFrame::Error { msg_id: Some(msg_id), reason }).await;
+                        }
+                    }
+                }
+                protocol::PupaFrame::Flash { msg_id } => {
+                    log::debug!(
+                        "Flash | msg_id: {} for [{}:{}]",
+                        msg_id,
+                        peer.ip(),
+                        peer.port()
+                    );
+
+                    ctx.metrics.flashes_received.inc();
+
+                    match check_rate(&ctx, &mut writer, current_player, msg_id, LimitedFrame::Flash).await {
+                        RateVerdict::Allowed => {}
+                        RateVerdict::Limited => continue,
+                        RateVerdict::Disconnect => break,
+                    }
+
+                    let flash_at = std::time::Instant::now();
+
+                    let outcome = {
+                        let mut message_stores = ctx.message_stores().await;
+                        let outcome = match message_stores.rooms.get_mut(&current_room) {
+                            Some(message_store) => message_store.flash(msg_id, current_player, flash_at, ctx.rules.as_ref()),
+                            None => Ok(FlashOutcome::Unknown),
+                        };
+                        ctx.update_message_store_size(&message_stores);
+                        outcome
+                    };
+
+                    let outcome = match outcome {
+                        Ok(outcome) => outcome,
+                        Err(reason) => {
+                            log::debug!(
+                                "Flash rejected | msg_id: {}, reason: {:?} for [{}:{}]",
+                                msg_id,
+                                reason,
+                                peer.ip(),
+                                peer.port()
+                            );
+                            ctx.metrics.flashes_rejected.inc();
+                            let _ = writer.send(protocol::PupaFrame::Error { msg_id: Some(msg_id), reason }).await;
+
+                            if reason == protocol::ErrorReason::TooEarly {
+                                let verdict = ctx.state().await.record_early_flash(current_player, &ctx.config());
+                                log::info!("User {} flashed too early | {:?}", current_player, verdict);
+
+                                if verdict == EarlyFlashVerdict::Banned {
+                                    let _ = writer.send(protocol::PupaFrame::NonAuthorized).await;
+                                    break;
+                                }
+                            }
+                            continue;
+                        }
+                    };
+
+                    match outcome {
+                        FlashOutcome::Won(message, attempt) => {
+                            // А вот и наш победитель
+                            let frame = ctx.award_win(&current_room, message, attempt).await;
+                            let _ = writer.send(frame).await;
+                        }
+                        // Первая попытка для сообщения, значит нам и планировать выбор победителя.
+                        // Сами мы ждать не можем, поэтому отдаем это отдельной задаче.
+                        FlashOutcome::Pending { resolve_at, first_attempt: true } => {
+                            tokio::spawn(resolve_after_window(Arc::clone(&ctx), current_room.clone(), msg_id, resolve_at));
+                        }
+                        FlashOutcome::Lost(winner) => {
+                            let _ = writer.send(protocol::PupaFrame::Lost {
+                                msg_id,
+                                winner: winner.player_id,
+                                winner_latency: winner.latency.as_millis() as u64,
+                            }).await;
+                        }
+                        FlashOutcome::Pending { .. } | FlashOutcome::Unknown => {}
+                    }
+                }
+                // Переход в другую комнату. Content и Flash дальше работают только в ней,
+                // а недоигранные сообщения старой комнаты для игрока пропадают
+                protocol::PupaFrame::JoinRoom { room } => {
+                    if !protocol::is_valid_room_name(&room) {
+                        let _ = writer.send(protocol::PupaFrame::Error { msg_id: None, reason: protocol::ErrorReason::InvalidRoomName }).await;
+                        continue;
+                    }
+
+                    log::debug!("JoinRoom | room: {} for [{}:{}]", room, peer.ip(), peer.port());
+                    let joined = ctx.state().await.join_room(current_player, &room, ctx.config().max_rooms);
+                    let dropped = match joined {
+                        Ok(dropped) => dropped,
+                        Err(reason) => {
+                            let _ = writer.send(protocol::PupaFrame::Error { msg_id: None, reason }).await;
+                            continue;
+                        }
+                    };
+                    if !dropped.is_empty() {
+                        log::debug!("Dropping idle rooms {:?}", dropped);
+                        let mut message_stores = ctx.message_stores().await;
+                        for room in dropped.iter() {
+                            message_stores.drop_room(room);
+                        }
+                        ctx.update_message_store_size(&message_stores);
+                    }
+                    current_room = room.clone();
+                    let _ = writer.send(protocol::PupaFrame::RoomJoined { room }).await;
+                }
+                protocol::PupaFrame::LeaveRoom => {
+                    log::debug!("LeaveRoom | room: {} for [{}:{}]", current_room, peer.ip(), peer.port());
+                    // Комната по умолчанию есть всегда, так что ни отказа, ни забытых комнат тут не бывает
+                    let _ = ctx.state().await.join_room(current_player, protocol::DEFAULT_ROOM, usize::MAX);
+                    current_room = protocol::DEFAULT_ROOM.to_string();
+                    let _ = writer.send(protocol::PupaFrame::RoomJoined { room: current_room.clone() }).await;
+                }
+                protocol::PupaFrame::ListRooms => {
+                    let rooms = ctx.state().await.list_rooms();
+                    let _ = writer.send(protocol::PupaFrame::Rooms { rooms }).await;
+                }
+                protocol::PupaFrame::Ping { nonce } => {
+                    let _ = writer.send(protocol::PupaFrame::Pong { nonce }).await;
+                }
+                protocol::PupaFrame::Pong { nonce } => {
+                    match outstanding_ping {
+                        Some((expected, sent_at)) if expected == nonce => {
+                            outstanding_ping = None;
+                            ctx.state().await.record_rtt(current_player, sent_at.elapsed().as_millis() as u64);
+                        }
+                        _ => log::debug!("Pong | unexpected nonce {} for [{}:{}]", nonce, peer.ip(), peer.port()),
+                    }
+                }
+                protocol::PupaFrame::SetProfile { name, avatar } => {
+                    let reason = if name.as_deref().is_some_and(|name| !protocol::is_valid_display_name(name)) {
+                        Some(protocol::ErrorReason::InvalidDisplayName)
+                    } else if avatar.as_deref().is_some_and(|avatar| !protocol::is_valid_avatar(avatar)) {
+                        Some(protocol::ErrorReason::InvalidAvatar)
+                    } else {
+                        None
+                    };
+                    if let Some(reason) = reason {
+                        let _ = writer.send(protocol::PupaFrame::Error { msg_id: None, reason }).await;
+                        continue;
+                    }
+
+                    log::debug!("SetProfile | name: {:?}, avatar: {:?} for [{}:{}]", name, avatar, peer.ip(), peer.port());
+                    if let Some(profile) = ctx.state().await.set_profile(current_player, name, avatar) {
+                        let _ = writer.send(profile).await;
+                    }
+                }
+                _ => {
+                    // Нам могли заново отправить фрейм с авторизацией.
+                    // В спецификации не указано, как на такое реагировать,
+                    // поэтому мы просто проигнорируем такой фрейм в рамках
+                    // сессии. Как и другие неожиданные фреймы
+                }
+            },
+            Some(Err(e)) => {
+                ctx.metrics.decode_errors.inc();
+                log::error!("error on decoding from socket; error = {:?}", e);
+            }
+                _ => {
+                    break;
+                }
+            },
+        }
+    }
+
+    // Все, наш клиент отключился.
+    // Поменяем ему статус на offline и отключим от канала.
+    ctx.state().await.disable_peer(current_player, &tx);
+    ctx.metrics.connected_peers.dec();
+
+    log::debug!("Peer disconnected [{}:{}]", peer.ip(), peer.port());
+}
+
+// Запись в сокет игрока. Полуоткрытое соединение может не принимать данные сколько угодно,
+// и хэндлер, застрявший на send, не дошел бы даже до проверки heartbeat. Поэтому каждая
+// запись ограничена heartbeat_timeout, а после первой неудачной хэндлер закрывает соединение
+struct PeerWriter {
+    inner: tokio_util::codec::FramedWrite<tokio::net::tcp::OwnedWriteHalf, protocol::PupaCodec>,
+    timeout: std::time::Duration,
+    failed: bool,
+}
+
+impl PeerWriter {
+    async fn send(&mut self, frame: protocol::PupaFrame) -> Result<(), std::io::Error> {
+        let result = tokio::time::timeout(self.timeout, self.inner.send(frame))
+            .await
+            .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()));
+        if result.is_err() {
+            self.failed = true;
+        }
+        result
+    }
+}
+
+// Следующий тик пингов. Если пинги выключены, то никогда
+async fn next_heartbeat(heartbeat: &mut Option<tokio::time::Interval>) {
+    match heartbeat {
+        Some(heartbeat) => {
+            heartbeat.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+// Проверка лимита частоты для Content и Flash. На превышение сразу отвечаем ошибкой,
+// а что делать с самим фреймом и соединением, решает хэндлер по вердикту
+async fn check_rate(
+    ctx: &Context,
+    writer: &mut PeerWriter,
+    player_id: uuid::Uuid,
+    msg_id: uuid::Uuid,
+    frame: LimitedFrame,
+) -> RateVerdict {
+    let verdict = ctx
+        .state()
+        .await
+        .check_rate(player_id, frame, &ctx.config());
+    if verdict == RateVerdict::Allowed {
+        return verdict;
+    }
+
+    log::debug!(
+        "{:?} from {} is rate limited | {:?}",
+        frame,
+        player_id,
+        verdict
+    );
+    ctx.metrics.rate_limited.inc();
+    let _ = writer
+        .send(protocol::PupaFrame::Error {
+            msg_id: Some(msg_id),
+            reason: protocol::ErrorReason::RateLimited,
+        })
+        .await;
+
+    if verdict == RateVerdict::Disconnect {
+        log::info!("User {} is disconnected for flooding", player_id);
+    }
+    verdict
+}
+
+async fn run_admin_command(
+    ctx: &Context,
+    command: protocol::AdminCommand,
+) -> Vec<protocol::PupaFrame> {
+    let result = |ok: bool, message: String| vec![protocol::PupaFrame::AdminResult { ok, message }];
+
+    match command {
+        protocol::AdminCommand::ListPeers => {
+            let mut peers = ctx
+                .state()
+                .await
+                .peers
+                .values()
+                .map(|peer| protocol::PupaFrame::PeerRecord {
+                    player_id: peer.player_id,
+                    name: peer.name.clone(),
+                    signature: peer.signature,
+                    address: peer.address.to_string(),
+                    online: peer.online,
+                    room: peer.room.clone(),
+                    banned: peer.banned,
+                })
+                .collect::<Vec<_>>();
+            peers.sort_by_key(|frame| match frame {
+                protocol::PupaFrame::PeerRecord { online, .. } => !*online,
+                _ => true,
+            });
+            peers
+        }
+        protocol::AdminCommand::Kick { player_id } => {
+            if ctx.state().await.kick(player_id, "kicked by admin") {
+                result(true, format!("{} is kicked", player_id))
+            } else {
+                result(false, format!("{} is not online", player_id))
+            }
+        }
+        protocol::AdminCommand::Ban { player_id } => {
+            let signature = {
+                let mut state = ctx.state().await;
+                let Some(signature) = state.set_banned(player_id, true) else {
+                    return result(false, format!("{} is unknown", player_id));
+                };
+                state.kick(player_id, "banned by admin");
+                signature
+            };
+            match ctx.save_ban(signature, true).await {
+                Ok(true) => result(true, format!("{} is banned", player_id)),
+                Ok(false) => result(
+                    true,
+                    format!(
+                        "{} is banned until restart, ACCESS_LIST_FILE is not set",
+                        player_id
+                    ),
+                ),
+                Err(e) => result(
+                    false,
+                    format!("{} is banned until restart, {}", player_id, e),
+                ),
+            }
+        }
+        protocol::AdminCommand::Unban { player_id } => {
+            let Some(signature) = ctx.state().await.set_banned(player_id, false) else {
+                return result(false, format!("{} is unknown", player_id));
+            };
+            match ctx.save_ban(signature, false).await {
+                Ok(_) => result(true, format!("{} is unbanned", player_id)),
+                Err(e) => result(
+                    false,
+                    format!("{} is unbanned until restart, {}", player_id, e),
+                ),
+            }
+        }
+        protocol::AdminCommand::Notice { text } => {
+            ctx.state()
+                .await
+                .notify(None, None, protocol::PupaFrame::SystemNotice { text });
+            result(true, "notice is sent".to_string())
+        }
+        protocol::AdminCommand::ResetStats => {
+            ctx.state().await.reset_stats();
+            ctx.winlog_store().await.clear();
+            result(true, "stats are reset".to_string())
+        }
+        protocol::AdminCommand::ReloadAccessList => match ctx.reload_access_list().await {
+            Ok(message) => result(true, message),
+            Err(message) => result(false, message),
+        },
+        protocol::AdminCommand::SetLimit { name, value } => {
+            let config = {
+                let mut config = ctx.config.write().unwrap();
+                if let Err(e) = config.set_limit(&name, value.as_deref()) {
+                    return result(false, e);
+                }
+                config.clone()
+            };
+            ctx.state().await.reset_limits(&config);
+            result(true, format!("{} is set to {:?}", name, value))
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn reload_access_list_on_sighup(ctx: Arc<Context>) {
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("SIGHUP handler should be installed");
+
+    while hangup.recv().await.is_some() {
+        match ctx.reload_access_list().await {
+            Ok(message) => log::info!("SIGHUP | {}", message),
+            // Кривой файл не должен ронять сервер, оставляем старые списки
+            Err(e) => log::error!("SIGHUP | access list is not reloaded: {}", e),
+        }
+    }
+}
+
+// Каждые round_duration закрываем сезон и сразу открываем следующий
+async fn run_rounds(ctx: Arc<Context>, round_duration: std::time::Duration) {
+    let mut timer =
+        tokio::time::interval_at(tokio::time::Instant::now() + round_duration, round_duration);
+
+    loop {
+        timer.tick().await;
+
+        let mut state = ctx.state().await;
+        let (season, winner) = state.end_season();
+        log::info!("Season {} is over, winner: {:?}", season, winner);
+
+        state.notify(
+            None,
+            None,
+            protocol::PupaFrame::RoundEnded { season, winner },
+        );
+        let frame = state.round_started_frame(round_duration);
+        state.notify(None, None, frame);
+    }
+}
+
+// Для правил с окном ждем, пока окно закроется, и выбираем победителя среди всех попыток.
+// Победитель может сидеть в другом хэндлере, поэтому Win отправляем через его канал в State.
+async fn resolve_after_window(
+    ctx: Arc<Context>,
+    room: String,
+    msg_id: uuid::Uuid,
+    resolve_at: std::time::Instant,
+) {
+    tokio::time::sleep_until(resolve_at.into()).await;
+
+    let resolved = {
+        let mut message_stores = ctx.message_stores().await;
+        let resolved = message_stores
+            .rooms
+            .get_mut(&room)
+            .and_then(|message_store| message_store.resolve(msg_id, ctx.rules.as_ref()));
+        ctx.update_message_store_size(&message_stores);
+        resolved
+    };
+
+    if let Some((message, attempt)) = resolved {
+        let frame = ctx.award_win(&room, message, attempt).await;
+        ctx.state().await.send_to(attempt.player_id, frame);
+    }
+}
+
+async fn run_api_handler(
+    socket: tokio::net::TcpStream,
+    peer: std::net::SocketAddr,
+    ctx: Arc<Context>,
+) {
+    log::debug!(
+        "New API server connection from {}:{}",
+        peer.ip(),
+        peer.port()
+    );
+
+    let codec = protocol::PupaCodec::new();
+    let (read_half, write_half) = socket.into_split();
+
+    // Дуплексный канал для общения с клиентом по TCP
+    let mut reader = tokio_util::codec::FramedRead::new(read_half, codec.clone());
+    let mut writer = tokio_util::codec::FramedWrite::new(write_half, codec);
+
+    // Права соединения. Пока клиент не прислал ключ, есть только то, что сервер дает без ключей
+    let mut scopes: std::collections::HashSet<Scope> = if ctx.api_keys.is_empty() {
+        Scope::OPEN.into()
+    } else {
+        std::collections::HashSet::new()
+    };
+
+    while let Some(result) = reader.next().await {
+        if let Some(scope) = result.as_ref().ok().and_then(required_scope) {
+            if !crate::auth::allows(&scopes, scope) {
+                log::warn!(
+                    "API | {:?} is not allowed for [{}:{}]",
+                    scope,
+                    peer.ip(),
+                    peer.port()
+                );
+                // Без ключа просим авторизоваться, с ключом говорим, что прав не хватает
+                let frame = if scopes.is_empty() {
+                    protocol::PupaFrame::NonAuthorized
+                } else {
+                    protocol::PupaFrame::Error {
+                        msg_id: None,
+                        reason: protocol::ErrorReason::Forbidden,
+                    }
+                };
+                let _ = writer.send(frame).await;
+                break;
+            }
+        }
+
+        match result {
+            Ok(protocol::PupaFrame::ApiAuthorize { key }) => match ctx.api_keys.scopes(&key) {
+                Some(key_scopes) => scopes = key_scopes.clone(),
+                // Ключей на сервере нет, сверять не с чем. С любым ключом все как без ключа,
+                // иначе клиент с API_KEY не смог бы ходить в открытый сервер
+                None if ctx.api_keys.is_empty() => {}
+                None => {
+                    log::warn!("API | unknown key from [{}:{}]", peer.ip(), peer.port());
+                    let _ = writer.send(protocol::PupaFrame::NonAuthorized).await;
+                    break;
+                }
+            },
+            Ok(protocol::PupaFrame::Admin { command }) => {
+                log::info!("Admin | {:?} from [{}:{}]", command, peer.ip(), peer.port());
+                for frame in run_admin_command(&ctx, command).await {
+                    let _ = writer.send(frame).await;
+                }
+
+                break;
+            }
+            // Тут у нас запрашивают лог победителей
+            Ok(protocol::PupaFrame::ShowWinners) => {
+                log::debug!("ShowWinnersLog | from [{}:{}] ", peer.ip(), peer.port());
+
+                let winners = ctx
+                    .state()
+                    .await
+                    .get_sorted_winners(ctx.scoring.elo.is_some());
+                for record in winners.iter() {
+                    let _ = writer
+                        .send(protocol::PupaFrame::WinnerRecord {
+                            player_id: record.player_id,
+                            name: record.name.clone(),
+                            online: record.online,
+                            wins: record.wins,
+                            score: record.score,
+                            rating: record.rating.round() as u32,
+                            messages_received: record.messages_received,
+                            messages_sent: record.messages_sent,
+                            early_flashes: record.early_flashes,
+                            invalid_content: record.invalid_content,
+                            rtt_ms: record.rtt.summary().map(|summary| summary.median_ms),
+                        })
+                        .await;
+                }
+
+                break;
+            }
+            // Тут у нас запрашивают лог побед
+            Ok(protocol::PupaFrame::ShowWinnersLog) => {
+                log::debug!("ShowWinnersLog | from [{}:{}] ", peer.ip(), peer.port());
+
+                let records = ctx.winlog_store().await.get_all();
+                for (player_id, timestamp, msg_id, latency_ms, room) in records.into_iter() {
+                    let _ = writer
+                        .send(protocol::PupaFrame::WinLogRecord {
+                            player_id,
+                            timestamp,
+                            msg_id,
+                            latency_ms,
+                            room,
+                        })
+                        .await;
+                }
+
+                break;
+            }
+            // Тут у нас запрашивают статистику задержек выигрышных флешей
+            Ok(protocol::PupaFrame::ShowLatencies) => {
+                log::debug!("ShowLatencies | from [{}:{}] ", peer.ip(), peer.port());
+
+                let leaderboard = ctx.state().await.get_latency_leaderboard();
+                for (player_id, summary) in leaderboard.into_iter() {
+                    let _ = writer
+                        .send(protocol::PupaFrame::LatencyRecord {
+                            player_id,
+                            samples: summary.count,
+                            min_ms: summary.min_ms,
+                            median_ms: summary.median_ms,
+                            p99_ms: summary.p99_ms,
+                        })
+                        .await;
+                }
+
+                break;
+            }
+            // Таблица победителей отдельной комнаты
+            Ok(protocol::PupaFrame::ShowRoomWinners { room }) => {
+                log::debug!(
+                    "ShowRoomWinners | room: {} from [{}:{}] ",
+                    room,
+                    peer.ip(),
+                    peer.port()
+                );
+
+                let winners = ctx.state().await.get_room_winners(&room);
+                for (player_id, online, standing) in winners.into_iter() {
+                    let _ = writer
+                        .send(protocol::PupaFrame::RoomWinnerRecord {
+                            room: room.clone(),
+                            player_id,
+                            online,
+                            wins: standing.wins,
+                            score: standing.score,
+                        })
+                        .await;
+                }
+
+                break;
+            }
+            // Список завершенных сезонов
+            Ok(protocol::PupaFrame::ShowSeasons) => {
+                log::debug!("ShowSeasons | from [{}:{}] ", peer.ip(), peer.port());
+
+                let seasons = ctx
+                    .state()
+                    .await
+                    .seasons
+                    .all()
+                    .iter()
+                    .map(|archived| protocol::PupaFrame::SeasonRecord {
+                        season: archived.season,
+                        started_at: archived.started_at,
+                        ended_at: archived.ended_at,
+                        players: archived.standings.len() as u32,
+                    })
+                    .collect::<Vec<_>>();
+                for frame in seasons.into_iter() {
+                    let _ = writer.send(frame).await;
+                }
+
+                break;
+            }
+            // Итоговая таблица одного из завершенных сезонов
+            Ok(protocol::PupaFrame::ShowSeasonWinners { season }) => {
+                log::debug!(
+                    "ShowSeasonWinners | season: {} from [{}:{}] ",
+                    season,
+                    peer.ip(),
+                    peer.port()
+                );
+
+                let standings = ctx
+                    .state()
+                    .await
+                    .seasons
+                    .get(season)
+                    .map(|archived| archived.standings.clone())
+                    .unwrap_or_default();
+                for standing in standings.into_iter() {
+                    let _ = writer
+                        .send(protocol::PupaFrame::SeasonWinnerRecord {
+                            season,
+                            player_id: standing.player_id,
+                            wins: standing.wins,
+                            score: standing.score,
+                        })
+                        .await;
+                }
+
+                break;
+            }
+            // Профиль игрока по публичному id
+            Ok(protocol::PupaFrame::ShowProfile { player_id }) => {
+                log::debug!(
+                    "ShowProfile | player: {} from [{}:{}] ",
+                    player_id,
+                    peer.ip(),
+                    peer.port()
+                );
+
+                let frame =
+                    ctx.state()
+                        .await
+                        .profile(player_id)
+                        .unwrap_or(protocol::PupaFrame::Error {
+                            msg_id: None,
+                            reason: protocol::ErrorReason::UnknownPlayer,
+                        });
+                let _ = writer.send(frame).await;
+
+                break;
+            }
+            Ok(protocol::PupaFrame::ListRooms) => {
+                log::debug!("ListRooms | from [{}:{}] ", peer.ip(), peer.port());
+
+                let rooms = ctx.state().await.list_rooms();
+                let _ = writer.send(protocol::PupaFrame::Rooms { rooms }).await;
+
+                break;
+            }
+            Err(e) => {
+                ctx.metrics.decode_errors.inc();
+                log::error!("error on decoding from socket; error = {:?}", e);
+            }
+            _ => {
+                //ignore
+            }
+        }
+    }
+
+    // Все, наш клиент отключился. Ну или мы отключили его по break;
+    log::debug!(
+        "API Server | Peer disconnected [{}:{}]",
+        peer.ip(),
+        peer.port()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use tokio::sync::mpsc;
+
+    // Сервер с настройками по умолчанию и правилами из ТЗ
+    fn context(config: Config) -> Arc<Context> {
+        let players = match &config.players_file {
+            Some(path) => PlayerRegistry::open(path).unwrap(),
+            None => PlayerRegistry::new(),
+        };
+        Arc::new(Context {
+            state: Mutex::new(State::new(players, config.resume_grace)),
+            message_stores: Mutex::new(MessageStores::default()),
+            winlog_store: Mutex::new(WinLogStore::new()),
+            metrics: Arc::new(Metrics::new()),
+            access: std::sync::RwLock::new(AccessList::new()),
+            access_file: Mutex::new(()),
+            api_keys: ApiKeys::new(),
+            config: std::sync::RwLock::new(config),
+            rules: Box::new(crate::rules::FirstFlashWins {
+                min_flash_delay: Duration::from_millis(1000),
+            }),
+            scoring: ScoringModel::default(),
+        })
+    }
+
+    // Игровой сервер на свободном порту, возвращает его адрес
+    async fn serve(ctx: Arc<Context>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (socket, peer) = listener.accept().await.unwrap();
+                tokio::spawn(run_game_handler(socket, peer, Arc::clone(&ctx)));
+            }
+        });
+        server_addr
+    }
+
+    // API сервер на свободном порту, возвращает его адрес
+    async fn serve_api(ctx: Arc<Context>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (socket, peer) = listener.accept().await.unwrap();
+                tokio::spawn(run_api_handler(socket, peer, Arc::clone(&ctx)));
+            }
+        });
+        server_addr
+    }
+
+    // Хэндлер разбирает фреймы по порядку, так что ответ на ListRooms значит, что все
+    // присланное до него уже учтено. Все, что пришло от сервера раньше Rooms, отдаем
+    async fn settle(
+        reader: &mut crate::ClientReader,
+        writer: &mut crate::ClientWriter,
+    ) -> Vec<protocol::PupaFrame> {
+        writer.list_rooms().await.unwrap();
+        let mut frames = Vec::new();
+        loop {
+            match reader.read().await {
+                Some(Ok(protocol::PupaFrame::Rooms { .. })) => return frames,
+                Some(Ok(frame)) => frames.push(frame),
+                other => panic!("server should answer ListRooms, got {:?}", other),
+            }
+        }
+    }
+
+    fn notice(text: &str) -> protocol::PupaFrame {
+        protocol::PupaFrame::SystemNotice {
+            text: text.to_string(),
+        }
+    }
+
+    // Игрок в новой сессии, как его собирает хэндлер после Authorize
+    fn peer(player_id: uuid::Uuid, channel: mpsc::Sender<protocol::PupaFrame>) -> Peer {
+        Peer {
+            player_id,
+            name: None,
+            avatar: None,
+            signature: uuid::Uuid::new_v4(),
+            online: true,
+            messages_received: 0,
+            messages_sent: 0,
+            wins: 0,
+            score: 0,
+            rating: crate::scoring::INITIAL_RATING,
+            win_latencies: LatencyStats::new(),
+            rtt: LatencyStats::new(),
+            early_flashes: 0,
+            invalid_content: 0,
+            banned: false,
+            limits: PlayerLimits {
+                content: None,
+                flash: None,
+                violations: 0,
+                violations_since: std::time::Instant::now(),
+            },
+            room: protocol::DEFAULT_ROOM.to_string(),
+            address: "127.0.0.1:1".parse().unwrap(),
+            channel: Some(channel),
+            resume_token: uuid::Uuid::new_v4(),
+            resumable_until: None,
+            missed: std::collections::VecDeque::new(),
+        }
+    }
+
+    // Новый игрок онлайн в комнате room, без сокета: все, что ему шлет сервер, лежит в канале
+    async fn joined(
+        ctx: &Context,
+        room: &str,
+    ) -> (uuid::Uuid, mpsc::Receiver<protocol::PupaFrame>) {
+        let player_id = uuid::Uuid::new_v4();
+        let (tx, rx) = mpsc::channel(4);
+        let mut state = ctx.state().await;
+        state.add_peer(peer(player_id, tx), None);
+        state.join_room(player_id, room, usize::MAX).unwrap();
+        (player_id, rx)
+    }
+
+    // Игрок сидит в комнате speed, отваливается и пропускает notices фреймов
+    fn dropped_peer(state: &mut State, notices: usize) -> (uuid::Uuid, uuid::Uuid) {
+        let player_id = uuid::Uuid::new_v4();
+        let (tx, _rx) = mpsc::channel(4);
+        let first = peer(player_id, tx.clone());
+        let token = first.resume_token;
+        assert!(state.add_peer(first, None).is_none());
+        state.join_room(player_id, "speed", usize::MAX).unwrap();
+        state.disable_peer(player_id, &tx);
+
+        let grace = state.resume_grace;
+        let peer = state.peers.get_mut(&player_id).unwrap();
+        for i in 0..notices {
+            assert!(peer.deliver(notice(&i.to_string()), grace));
+        }
+        (player_id, token)
+    }
+
+    #[test]
+    fn test_resume_session() {
+        let mut state = State::new(PlayerRegistry::new(), Duration::from_secs(60));
+
+        // С верным токеном в окне возвращаемся в свою комнату и забираем пропущенное,
+        // причем копится не больше MAX_MISSED_FRAMES, старые выкидываются
+        let (player_id, token) = dropped_peer(&mut state, MAX_MISSED_FRAMES + 5);
+        let (tx, _rx) = mpsc::channel(4);
+        let (room, missed) = state.add_peer(peer(player_id, tx), Some(token)).unwrap();
+        assert_eq!(room, "speed");
+        assert_eq!(missed.len(), MAX_MISSED_FRAMES);
+        assert_eq!(missed.first(), Some(&notice("5")));
+        assert_eq!(
+            missed.last(),
+            Some(&notice(&(MAX_MISSED_FRAMES + 4).to_string()))
+        );
+        assert!(state.peers[&player_id].online);
+
+        // С чужим токеном сессия начинается заново в комнате по умолчанию
+        let (player_id, _) = dropped_peer(&mut state, 1);
+        let (tx, _rx) = mpsc::channel(4);
+        let wrong = Some(uuid::Uuid::new_v4());
+        assert!(state.add_peer(peer(player_id, tx), wrong).is_none());
+        assert_eq!(state.peers[&player_id].room, protocol::DEFAULT_ROOM);
+        assert!(state.peers[&player_id].missed.is_empty());
+
+        // После окна не помогает и верный токен, а фреймы уже не копятся
+        let mut state = State::new(PlayerRegistry::new(), Duration::ZERO);
+        let (player_id, token) = dropped_peer(&mut state, 0);
+        let grace = state.resume_grace;
+        assert!(!state
+            .peers
+            .get_mut(&player_id)
+            .unwrap()
+            .deliver(notice("late"), grace));
+        let (tx, _rx) = mpsc::channel(4);
+        assert!(state.add_peer(peer(player_id, tx), Some(token)).is_none());
+        assert_eq!(state.peers[&player_id].room, protocol::DEFAULT_ROOM);
+    }
+
+    #[test]
+    fn test_resume_takes_over_online_session() {
+        let mut state = State::new(PlayerRegistry::new(), Duration::from_secs(60));
+        let player_id = uuid::Uuid::new_v4();
+
+        let (old_tx, mut old_rx) = mpsc::channel(4);
+        let first = peer(player_id, old_tx.clone());
+        let token = first.resume_token;
+        state.add_peer(first, None);
+        state.join_room(player_id, "speed", usize::MAX).unwrap();
+
+        // Старое соединение еще считается живым, а клиент уже пришел с токеном
+        let (new_tx, mut new_rx) = mpsc::channel(4);
+        let (room, missed) = state
+            .add_peer(peer(player_id, new_tx), Some(token))
+            .unwrap();
+        assert_eq!(room, "speed");
+        assert!(missed.is_empty());
+        assert!(matches!(
+            old_rx.try_recv(),
+            Ok(protocol::PupaFrame::Kicked { .. })
+        ));
+
+        // Старый хэндлер закрывается и новую сессию не трогает
+        state.disable_peer(player_id, &old_tx);
+        let grace = state.resume_grace;
+        let active = state.peers.get_mut(&player_id).unwrap();
+        assert!(active.online);
+        assert!(active.deliver(notice("hi"), grace));
+        assert_eq!(new_rx.try_recv().ok(), Some(notice("hi")));
+        assert!(old_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_early_flash_penalty_and_ban() {
+        let config = Config {
+            early_flash_penalty_after: Some(2),
+            early_flash_ban_after: Some(4),
+            ..Config::from_env()
+        };
+        let mut state = State::new(PlayerRegistry::new(), Duration::from_secs(60));
+        let player_id = uuid::Uuid::new_v4();
+        let (tx, _rx) = mpsc::channel(4);
+        state.add_peer(peer(player_id, tx), None);
+        state.join_room(player_id, "speed", usize::MAX).unwrap();
+        for points in [2, 3, 4] {
+            state.update_winners(player_id, "speed", 1300, points);
+        }
+        let standings = |state: &State| {
+            let peer = &state.peers[&player_id];
+            let room = state.rooms["speed"][&player_id];
+            (peer.wins, peer.score, room.wins, room.score)
+        };
+
+        assert_eq!(
+            state.record_early_flash(player_id, &config),
+            EarlyFlashVerdict::Warned
+        );
+        assert_eq!(standings(&state), (3, 9, 3, 9));
+
+        // Каждый следующий снимает победу и ее среднюю цену и из общей таблицы, и из комнаты
+        assert_eq!(
+            state.record_early_flash(player_id, &config),
+            EarlyFlashVerdict::Penalised
+        );
+        assert_eq!(standings(&state), (2, 6, 2, 6));
+        assert_eq!(
+            state.record_early_flash(player_id, &config),
+            EarlyFlashVerdict::Penalised
+        );
+        assert_eq!(standings(&state), (1, 3, 1, 3));
+        assert!(!state.is_banned(player_id));
+
+        assert_eq!(
+            state.record_early_flash(player_id, &config),
+            EarlyFlashVerdict::Banned
+        );
+        assert!(state.is_banned(player_id));
+        assert_eq!(standings(&state), (1, 3, 1, 3));
+    }
+
+    #[test]
+    fn test_rate_violations_disconnect_within_window() {
+        let config = Config {
+            flash_limit: Some(RateLimit {
+                per_sec: 0.001,
+                burst: 1.0,
+            }),
+            content_limit: None,
+            rate_limit_disconnect_after: Some(3),
+            rate_limit_window: Duration::from_secs(10),
+            ..Config::from_env()
+        };
+        let mut state = State::new(PlayerRegistry::new(), Duration::from_secs(60));
+        let player_id = uuid::Uuid::new_v4();
+        let (tx, _rx) = mpsc::channel(4);
+        let mut flooder = peer(player_id, tx);
+        flooder.limits = PlayerLimits::new(&config);
+        state.add_peer(flooder, None);
+        let mut flash = || state.check_rate(player_id, LimitedFrame::Flash, &config);
+
+        assert_eq!(flash(), RateVerdict::Allowed);
+        assert_eq!(flash(), RateVerdict::Limited);
+        assert_eq!(flash(), RateVerdict::Limited);
+
+        // Нарушения старше окна прощаются, счет начинается заново
+        let limits = &mut state.peers.get_mut(&player_id).unwrap().limits;
+        limits.violations_since = std::time::Instant::now()
+            .checked_sub(Duration::from_secs(11))
+            .unwrap();
+        let mut flash = || state.check_rate(player_id, LimitedFrame::Flash, &config);
+        assert_eq!(flash(), RateVerdict::Limited);
+        assert_eq!(flash(), RateVerdict::Limited);
+        assert_eq!(flash(), RateVerdict::Disconnect);
+
+        // Content считается своим ведром, его лимит выключен
+        assert_eq!(
+            state.check_rate(player_id, LimitedFrame::Content, &config),
+            RateVerdict::Allowed
+        );
+    }
+
+    #[test]
+    fn test_stale_handler_keeps_new_session() {
+        let mut state = State::new(PlayerRegistry::new(), Duration::from_secs(60));
+        let player_id = uuid::Uuid::new_v4();
+
+        let (old_tx, _old_rx) = mpsc::channel(4);
+        state.add_peer(peer(player_id, old_tx.clone()), None);
+        let (new_tx, mut new_rx) = mpsc::channel(4);
+        state.add_peer(peer(player_id, new_tx.clone()), None);
+
+        // Старый хэндлер доработал уже после переподключения, новая сессия остается онлайн
+        state.disable_peer(player_id, &old_tx);
+        let grace = state.resume_grace;
+        let active = state.peers.get_mut(&player_id).unwrap();
+        assert!(active.online);
+        assert!(active.deliver(notice("hi"), grace));
+        assert_eq!(new_rx.try_recv().ok(), Some(notice("hi")));
+
+        state.disable_peer(player_id, &new_tx);
+        assert!(!state.peers[&player_id].online);
+        assert!(state.peers[&player_id].is_resumable());
+    }
+
+    #[tokio::test]
+    async fn test_silent_peer_goes_offline() {
+        let ctx = context(Config {
+            heartbeat_interval: Some(Duration::from_millis(20)),
+            heartbeat_timeout: Duration::from_millis(100),
+            ..Config::from_env()
+        });
+        let server_addr = serve(Arc::clone(&ctx)).await;
+
+        // Подключаемся и больше ничего не шлем, даже Pong
+        let (_reader, _writer, session) =
+            crate::connect_to_game_server(&server_addr, None, None, None)
+                .await
+                .unwrap();
+        assert!(ctx.state().await.peers[&session.player_id].online);
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert!(!ctx.state().await.peers[&session.player_id].online);
+        assert_eq!(ctx.metrics.heartbeat_timeouts.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rtt_only_from_matching_pong() {
+        let ctx = context(Config {
+            heartbeat_interval: Some(Duration::from_millis(500)),
+            ..Config::from_env()
+        });
+        let server_addr = serve(Arc::clone(&ctx)).await;
+        let (mut reader, mut writer, session) =
+            crate::connect_to_game_server(&server_addr, None, None, None)
+                .await
+                .unwrap();
+
+        let Some(Ok(protocol::PupaFrame::Ping { nonce })) = reader.read().await else {
+            panic!("server should ping us");
+        };
+        writer.write_pong(nonce + 1).await.unwrap();
+        settle(&mut reader, &mut writer).await;
+        assert!(ctx.state().await.peers[&session.player_id]
+            .rtt
+            .summary()
+            .is_none());
+
+        writer.write_pong(nonce).await.unwrap();
+        settle(&mut reader, &mut writer).await;
+        let rtt = ctx.state().await.peers[&session.player_id].rtt.summary();
+        assert_eq!(rtt.map(|rtt| rtt.count), Some(1));
+
+        // Повтор того же Pong второй раз не считается
+        writer.write_pong(nonce).await.unwrap();
+        settle(&mut reader, &mut writer).await;
+        let rtt = ctx.state().await.peers[&session.player_id].rtt.summary();
+        assert_eq!(rtt.map(|rtt| rtt.count), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_admin_kick_and_ban_end_the_session() {
+        let ctx = context(Config::from_env());
+        let server_addr = serve(Arc::clone(&ctx)).await;
+        let admin = |command| run_admin_command(&ctx, command);
+        let (mut reader, _writer, session) =
+            crate::connect_to_game_server(&server_addr, None, None, None)
+                .await
+                .unwrap();
+        let player_id = session.player_id;
+
+        assert_eq!(
+            admin(protocol::AdminCommand::Kick { player_id }).await,
+            vec![protocol::PupaFrame::AdminResult {
+                ok: true,
+                message: format!("{} is kicked", player_id)
+            }]
+        );
+        assert!(matches!(
+            reader.read().await,
+            Some(Ok(protocol::PupaFrame::Kicked { .. }))
+        ));
+        assert!(!ctx.state().await.peers[&player_id].online);
+
+        // Токен выгнанной сессии больше не действует, игрок начинает заново
+        let (mut reader, _writer, session) = crate::connect_to_game_server(
+            &server_addr,
+            Some(session.signature),
+            None,
+            Some(session.resume_token),
+        )
+        .await
+        .unwrap();
+        assert_eq!(session.player_id, player_id);
+        assert!(!session.resumed);
+
+        let reply = admin(protocol::AdminCommand::Ban { player_id }).await;
+        assert!(matches!(
+            &reply[..],
+            [protocol::PupaFrame::AdminResult { ok: true, .. }]
+        ));
+        assert!(matches!(
+            reader.read().await,
+            Some(Ok(protocol::PupaFrame::Kicked { .. }))
+        ));
+        let Err(rejected) = crate::connect_to_game_server(
+            &server_addr,
+            Some(session.signature),
+            None,
+            Some(session.resume_token),
+        )
+        .await
+        else {
+            panic!("banned player should be rejected");
+        };
+        assert!(matches!(
+            rejected.downcast_ref::<crate::Rejected>(),
+            Some(crate::Rejected(protocol::PupaFrame::Banned { .. }))
+        ));
+
+        let stranger = uuid::Uuid::new_v4();
+        assert!(matches!(
+            &admin(protocol::AdminCommand::Kick {
+                player_id: stranger
+            })
+            .await[..],
+            [protocol::PupaFrame::AdminResult { ok: false, .. }]
+        ));
+        assert!(matches!(
+            &admin(protocol::AdminCommand::Ban {
+                player_id: stranger
+            })
+            .await[..],
+            [protocol::PupaFrame::AdminResult { ok: false, .. }]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_admin_set_limit_resets_buckets() {
+        let ctx = context(Config {
+            content_limit: None,
+            ..Config::from_env()
+        });
+        let player_id = uuid::Uuid::new_v4();
+        let (tx, _rx) = mpsc::channel(4);
+        ctx.state().await.add_peer(peer(player_id, tx), None);
+        let set_limit = |name: &str, value: &str| {
+            run_admin_command(
+                &ctx,
+                protocol::AdminCommand::SetLimit {
+                    name: name.to_string(),
+                    value: Some(value.to_string()),
+                },
+            )
+        };
+        let check_content = || async {
+            let config = ctx.config().clone();
+            ctx.state()
+                .await
+                .check_rate(player_id, LimitedFrame::Content, &config)
+        };
+
+        assert_eq!(check_content().await, RateVerdict::Allowed);
+        assert_eq!(check_content().await, RateVerdict::Allowed);
+
+        // Новый лимит сразу действует и на тех, кто уже играет
+        for (name, value) in [("CONTENT_RATE_PER_SEC", "0.001"), ("CONTENT_BURST", "1")] {
+            assert!(matches!(
+                &set_limit(name, value).await[..],
+                [protocol::PupaFrame::AdminResult { ok: true, .. }]
+            ));
+        }
+        assert_eq!(check_content().await, RateVerdict::Allowed);
+        assert_eq!(check_content().await, RateVerdict::Limited);
+
+        assert!(matches!(
+            &set_limit("CONTENT_BURST", "many").await[..],
+            [protocol::PupaFrame::AdminResult { ok: false, .. }]
+        ));
+        assert!(matches!(
+            &set_limit("PUPA_BURST", "1").await[..],
+            [protocol::PupaFrame::AdminResult { ok: false, .. }]
+        ));
+        assert_eq!(
+            ctx.config().content_limit.map(|limit| limit.burst),
+            Some(1.0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_registration_and_profile_survive_restart() {
+        let path = std::env::temp_dir().join(format!("nltt-players-{}", uuid::Uuid::new_v4()));
+        let config = Config {
+            players_file: Some(path.to_str().unwrap().to_string()),
+            require_registration: true,
+            ..Config::from_env()
+        };
+
+        let ctx = context(config.clone());
+        let server_addr = serve(Arc::clone(&ctx)).await;
+        let (mut reader, mut writer, session) =
+            crate::register_on_game_server(&server_addr, Some("Pupa".to_string()))
+                .await
+                .unwrap();
+
+        writer
+            .set_profile(Some("Pupa".to_string()), Some("\u{1F600}".to_string()))
+            .await
+            .unwrap();
+        let profile = protocol::PupaFrame::Profile {
+            player_id: session.player_id,
+            name: Some("Pupa".to_string()),
+            avatar: Some("\u{1F600}".to_string()),
+        };
+        assert_eq!(
+            settle(&mut reader, &mut writer).await,
+            vec![profile.clone()]
+        );
+
+        writer
+            .set_profile(
+                Some("Pupa".to_string()),
+                Some("too long avatar".to_string()),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            settle(&mut reader, &mut writer).await,
+            vec![protocol::PupaFrame::Error {
+                msg_id: None,
+                reason: protocol::ErrorReason::InvalidAvatar
+            }]
+        );
+
+        // Перезапуск: профиль виден в API еще до входа, а подпись по-прежнему пускают
+        let ctx = context(config);
+        assert_eq!(
+            ctx.state().await.profile(session.player_id),
+            Some(profile.clone())
+        );
+        let server_addr = serve(Arc::clone(&ctx)).await;
+        let (_reader, _writer, resumed) =
+            crate::connect_to_game_server(&server_addr, Some(session.signature), None, None)
+                .await
+                .unwrap();
+        assert_eq!(resumed.player_id, session.player_id);
+        assert_eq!(ctx.state().await.profile(session.player_id), Some(profile));
+
+        // А незнакомую подпись с REQUIRE_REGISTRATION нет
+        let Err(rejected) =
+            crate::connect_to_game_server(&server_addr, Some(uuid::Uuid::new_v4()), None, None)
+                .await
+        else {
+            panic!("unregistered signature should be rejected");
+        };
+        assert_eq!(
+            rejected.downcast_ref::<crate::Rejected>(),
+            Some(&crate::Rejected(protocol::PupaFrame::NonAuthorized))
+        );
+        assert_eq!(ctx.state().await.players.len(), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_register_rate_limit() {
+        let ctx = context(Config {
+            register_limit: Some(RateLimit {
+                per_sec: 0.001,
+                burst: 2.0,
+            }),
+            ..Config::from_env()
+        });
+        let server_addr = serve(Arc::clone(&ctx)).await;
+
+        for _ in 0..2 {
+            assert!(crate::register_on_game_server(&server_addr, None)
+                .await
+                .is_ok());
+        }
+        let Err(rejected) = crate::register_on_game_server(&server_addr, None).await else {
+            panic!("third registration should be rate limited");
+        };
+        assert_eq!(
+            rejected.downcast_ref::<crate::Rejected>(),
+            Some(&crate::Rejected(protocol::PupaFrame::Error {
+                msg_id: None,
+                reason: protocol::ErrorReason::RateLimited
+            }))
+        );
+        assert_eq!(ctx.state().await.players.len(), 2);
+
+        // Ведро у каждого адреса свое, а IPv4 в IPv6 считается тем же адресом
+        let config = ctx.config().clone();
+        let mut state = ctx.state().await;
+        let mapped = std::net::Ipv4Addr::LOCALHOST.to_ipv6_mapped().into();
+        assert!(!state.check_register_rate(mapped, &config));
+        assert!(state.check_register_rate("127.0.0.2".parse().unwrap(), &config));
+    }
+
+    #[tokio::test]
+    async fn test_award_win_notifies_losers_and_room() {
+        let ctx = context(Config {
+            announce_claims: true,
+            ..Config::from_env()
+        });
+        let (winner, mut winner_rx) = joined(&ctx, "speed").await;
+        let (loser, mut loser_rx) = joined(&ctx, "speed").await;
+        let (watcher, mut watcher_rx) = joined(&ctx, "speed").await;
+        let (_, mut outsider_rx) = joined(&ctx, protocol::DEFAULT_ROOM).await;
+
+        let msg_id = uuid::Uuid::new_v4();
+        let attempt = |player_id, latency_ms| FlashAttempt {
+            player_id,
+            latency: Duration::from_millis(latency_ms),
+        };
+        let mut message = StoredMessage::new(
+            msg_id,
+            uuid::Uuid::new_v4(),
+            vec![1, 2, 3],
+            std::time::Instant::now(),
+            [winner, loser, watcher].into_iter().collect(),
+        );
+        message.attempts = vec![attempt(loser, 1100), attempt(winner, 1300)];
+
+        let frame = ctx.award_win("speed", message, attempt(winner, 1300)).await;
+        assert_eq!(
+            frame,
+            protocol::PupaFrame::Win {
+                msg_id,
+                body: vec![1, 2, 3]
+            }
+        );
+
+        // Проигравший претендент узнает победителя, вся комната узнает, что сообщение забрано
+        let claimed = protocol::PupaFrame::MessageClaimed { msg_id, winner };
+        assert_eq!(
+            loser_rx.try_recv().ok(),
+            Some(protocol::PupaFrame::Lost {
+                msg_id,
+                winner,
+                winner_latency: 1300
+            })
+        );
+        assert_eq!(loser_rx.try_recv().ok(), Some(claimed.clone()));
+        assert_eq!(watcher_rx.try_recv().ok(), Some(claimed));
+        assert!(watcher_rx.try_recv().is_err());
+        // Победитель получает только Win от своего хэндлера, а другие комнаты ничего
+        assert!(winner_rx.try_recv().is_err());
+        assert!(outsider_rx.try_recv().is_err());
+
+        let state = ctx.state().await;
+        assert_eq!(state.peers[&winner].wins, 1);
+        assert_eq!(state.get_room_winners("speed")[0].0, winner);
+    }
+
+    #[tokio::test]
+    async fn test_open_api_accepts_any_key() {
+        use crate::api_client::{ApiClient, ApiError, WinnersQuery};
+
+        let ctx = context(Config::from_env());
+        let (player_id, _rx) = joined(&ctx, protocol::DEFAULT_ROOM).await;
+        let server_addr = serve_api(Arc::clone(&ctx)).await;
+
+        for key in [None, Some("left-from-another-server".to_string())] {
+            let api = ApiClient::new(&server_addr, key);
+            let winners = api.winners(WinnersQuery::Live).await.unwrap();
+            assert_eq!(winners.len(), 1);
+            assert_eq!(winners[0].player_id, player_id);
+
+            // Открыт сервер только на чтение
+            assert!(matches!(
+                api.admin(protocol::AdminCommand::ResetStats).await,
+                Err(ApiError::Rejected(protocol::ErrorReason::Forbidden))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_rooms_drops_idle_rooms() {
+        let ctx = context(Config {
+            max_rooms: 2,
+            ..Config::from_env()
+        });
+        let server_addr = serve(Arc::clone(&ctx)).await;
+        let connect = || crate::connect_to_game_server(&server_addr, None, None, None);
+        let (mut reader, mut writer, _) = connect().await.unwrap();
+
+        writer.join_room("idle").await.unwrap();
+        assert_eq!(
+            settle(&mut reader, &mut writer).await,
+            vec![protocol::PupaFrame::RoomJoined {
+                room: "idle".to_string()
+            }]
+        );
+
+        // В комнате разыграли сообщение и ушли из нее, осталась только память о msg_id
+        let msg_id = uuid::Uuid::new_v4();
+        {
+            let recipient = uuid::Uuid::new_v4();
+            let broadcast_at = std::time::Instant::now();
+            let mut message_stores = ctx.message_stores().await;
+            let message_store = message_stores.rooms.entry("idle".to_string()).or_default();
+            message_store
+                .insert(StoredMessage::new(
+                    msg_id,
+                    uuid::Uuid::new_v4(),
+                    vec![],
+                    broadcast_at,
+                    [recipient].into_iter().collect(),
+                ))
+                .unwrap();
+            let flashed_at = broadcast_at + Duration::from_millis(1300);
+            assert!(matches!(
+                message_store.flash(msg_id, recipient, flashed_at, ctx.rules.as_ref()),
+                Ok(FlashOutcome::Won(..))
+            ));
+        }
+        writer.leave_room().await.unwrap();
+        settle(&mut reader, &mut writer).await;
+
+        // Третьей комнате место освобождает пустая комната без таблицы
+        let (mut other_reader, mut other_writer, other) = connect().await.unwrap();
+        other_writer.join_room("busy").await.unwrap();
+        assert_eq!(
+            settle(&mut other_reader, &mut other_writer).await,
+            vec![protocol::PupaFrame::RoomJoined {
+                room: "busy".to_string()
+            }]
+        );
+        assert!(!ctx.state().await.rooms.contains_key("idle"));
+        assert!(!ctx.message_stores().await.rooms.contains_key("idle"));
+
+        // Но сообщения забытой комнаты заново не разыграть
+        writer
+            .write_content(msg_id, crate::generate_random_text())
+            .await
+            .unwrap();
+        assert_eq!(
+            settle(&mut reader, &mut writer).await,
+            vec![protocol::PupaFrame::Error {
+                msg_id: Some(msg_id),
+                reason: protocol::ErrorReason::DuplicateMessage
+            }]
+        );
+
+        // Комнату, где кто-то сидит, и комнату с таблицей не забываем
+        let too_many = vec![protocol::PupaFrame::Error {
+            msg_id: None,
+            reason: protocol::ErrorReason::TooManyRooms,
+        }];
+        writer.join_room("third").await.unwrap();
+        assert_eq!(settle(&mut reader, &mut writer).await, too_many);
+
+        ctx.state()
+            .await
+            .update_winners(other.player_id, "busy", 1300, 1);
+        other_writer.leave_room().await.unwrap();
+        settle(&mut other_reader, &mut other_writer).await;
+        writer.join_room("third").await.unwrap();
+        assert_eq!(settle(&mut reader, &mut writer).await, too_many);
+        assert!(ctx.state().await.rooms.contains_key("busy"));
+    }
+}
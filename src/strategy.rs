@@ -0,0 +1,177 @@
+// Когда бот отправляет Flash.
+//
+// В client.rs задержка из ТЗ (1 секунда + random от 250 до 500 ms) была зашита прямо
+// в таймер. Здесь она вынесена в трейт, чтобы гонять ботов с разным поведением против
+// одного сервера и смотреть, что выигрывает при тех или иных правилах. Стратегия
+// решает, через сколько после получения Content отправить Flash и отправлять ли вообще,
+// а заодно узнает, за сколько забирали сообщения победители.
+
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::latency::LatencyStats;
+
+pub trait FlashStrategy: Send {
+    fn name(&self) -> &'static str;
+
+    // Через сколько после получения Content отправить Flash. None - не бороться за сообщение
+    fn flash_delay(&mut self, msg_id: uuid::Uuid) -> Option<Duration>;
+
+    // Сообщение забрал кто-то другой, winner_latency из Lost
+    fn observe_win_latency(&mut self, _latency: Duration) {}
+}
+
+// Задержка из ТЗ: base и сверху случайно от min_extra до max_extra
+pub struct RandomDelay {
+    pub base: Duration,
+    pub min_extra: Duration,
+    pub max_extra: Duration,
+}
+
+impl Default for RandomDelay {
+    fn default() -> Self {
+        RandomDelay {
+            base: Duration::from_secs(1),
+            min_extra: Duration::from_millis(250),
+            max_extra: Duration::from_millis(500),
+        }
+    }
+}
+
+impl FlashStrategy for RandomDelay {
+    fn name(&self) -> &'static str {
+        "random"
+    }
+
+    fn flash_delay(&mut self, _msg_id: uuid::Uuid) -> Option<Duration> {
+        let extra = rand::thread_rng().gen_range(self.min_extra..=self.max_extra);
+        Some(self.base + extra)
+    }
+}
+
+pub struct FixedDelay(pub Duration);
+
+impl FlashStrategy for FixedDelay {
+    fn name(&self) -> &'static str {
+        "fixed"
+    }
+
+    fn flash_delay(&mut self, _msg_id: uuid::Uuid) -> Option<Duration> {
+        Some(self.0)
+    }
+}
+
+// Подстраивается под соперников: отправляет Flash на margin раньше медианы задержек
+// победителей, но не раньше min_delay, чтобы не нарваться на штраф за ранний Flash.
+// Пока не видели ни одной чужой победы, ведет себя как initial
+pub struct AdaptiveDelay {
+    pub min_delay: Duration,
+    pub margin: Duration,
+    pub initial: RandomDelay,
+    observed: LatencyStats,
+}
+
+impl AdaptiveDelay {
+    pub fn new(min_delay: Duration, margin: Duration) -> Self {
+        AdaptiveDelay {
+            min_delay,
+            margin,
+            initial: RandomDelay::default(),
+            observed: LatencyStats::new(),
+        }
+    }
+}
+
+impl FlashStrategy for AdaptiveDelay {
+    fn name(&self) -> &'static str {
+        "adaptive"
+    }
+
+    fn flash_delay(&mut self, msg_id: uuid::Uuid) -> Option<Duration> {
+        let Some(summary) = self.observed.summary() else {
+            return self.initial.flash_delay(msg_id);
+        };
+
+        let target = Duration::from_millis(summary.median_ms).saturating_sub(self.margin);
+        Some(target.max(self.min_delay))
+    }
+
+    fn observe_win_latency(&mut self, latency: Duration) {
+        self.observed.record(latency.as_millis() as u64);
+    }
+}
+
+// Обертка над любой стратегией, которая пропускает часть сообщений с вероятностью probability
+pub struct SkipSome {
+    pub inner: Box<dyn FlashStrategy>,
+    pub probability: f64,
+}
+
+impl FlashStrategy for SkipSome {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn flash_delay(&mut self, msg_id: uuid::Uuid) -> Option<Duration> {
+        if rand::thread_rng().gen_bool(self.probability) {
+            return None;
+        }
+
+        self.inner.flash_delay(msg_id)
+    }
+
+    fn observe_win_latency(&mut self, latency: Duration) {
+        self.inner.observe_win_latency(latency)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adaptive_delay_follows_winners_but_not_below_min() {
+        let mut strategy =
+            AdaptiveDelay::new(Duration::from_millis(1_000), Duration::from_millis(50));
+        let msg_id = uuid::Uuid::new_v4();
+
+        let initial = strategy.flash_delay(msg_id).unwrap();
+        assert!(initial >= Duration::from_millis(1_250) && initial <= Duration::from_millis(1_500));
+
+        for latency in [1_200, 1_300, 1_400] {
+            strategy.observe_win_latency(Duration::from_millis(latency));
+        }
+        assert_eq!(
+            strategy.flash_delay(msg_id),
+            Some(Duration::from_millis(1_250))
+        );
+
+        for _ in 0..10 {
+            strategy.observe_win_latency(Duration::from_millis(900));
+        }
+        assert_eq!(
+            strategy.flash_delay(msg_id),
+            Some(Duration::from_millis(1_000))
+        );
+    }
+
+    #[test]
+    fn test_skip_some_extremes() {
+        let msg_id = uuid::Uuid::new_v4();
+        let mut always = SkipSome {
+            inner: Box::new(FixedDelay(Duration::from_millis(1_100))),
+            probability: 1.0,
+        };
+        let mut never = SkipSome {
+            inner: Box::new(FixedDelay(Duration::from_millis(1_100))),
+            probability: 0.0,
+        };
+
+        assert_eq!(always.flash_delay(msg_id), None);
+        assert_eq!(
+            never.flash_delay(msg_id),
+            Some(Duration::from_millis(1_100))
+        );
+    }
+}
@@ -0,0 +1,114 @@
+// Проверка тела Content на сервере.
+//
+// По ТЗ клиент шлет текст длиной от 30 до 100 байт, но сервер до сих пор принимал что угодно,
+// хоть пустое тело, хоть мегабайт. Политика настраивается на сервере, по умолчанию
+// проверяется только длина из ТЗ.
+
+use crate::protocol::ErrorReason;
+
+// Какие символы разрешены в теле
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Charset {
+    Any,
+    // Печатные ASCII символы и пробел
+    Printable,
+    // Только латиница и цифры, как генерирует generate_random_text
+    Alphanumeric,
+}
+
+impl std::str::FromStr for Charset {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "any" => Ok(Charset::Any),
+            "printable" => Ok(Charset::Printable),
+            "alphanumeric" => Ok(Charset::Alphanumeric),
+            other => Err(format!("unknown charset {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ContentPolicy {
+    pub min_len: usize,
+    pub max_len: usize,
+    pub charset: Charset,
+    pub require_utf8: bool,
+}
+
+impl Default for ContentPolicy {
+    fn default() -> Self {
+        ContentPolicy {
+            min_len: 30,
+            max_len: 100,
+            charset: Charset::Any,
+            require_utf8: false,
+        }
+    }
+}
+
+impl ContentPolicy {
+    pub fn validate(&self, body: &[u8]) -> Result<(), ErrorReason> {
+        if body.len() < self.min_len {
+            return Err(ErrorReason::BodyTooShort);
+        }
+        if body.len() > self.max_len {
+            return Err(ErrorReason::BodyTooLong);
+        }
+        if self.require_utf8 && std::str::from_utf8(body).is_err() {
+            return Err(ErrorReason::InvalidBodyEncoding);
+        }
+
+        let allowed = match self.charset {
+            Charset::Any => true,
+            Charset::Printable => body
+                .iter()
+                .all(|byte| byte.is_ascii_graphic() || *byte == b' '),
+            Charset::Alphanumeric => body.iter().all(u8::is_ascii_alphanumeric),
+        };
+        if !allowed {
+            return Err(ErrorReason::InvalidBodyCharset);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_policy() {
+        let policy = ContentPolicy {
+            min_len: 3,
+            max_len: 5,
+            charset: Charset::Alphanumeric,
+            require_utf8: true,
+        };
+
+        assert_eq!(policy.validate(b"abc12"), Ok(()));
+        assert_eq!(policy.validate(b"ab"), Err(ErrorReason::BodyTooShort));
+        assert_eq!(policy.validate(b"abcdef"), Err(ErrorReason::BodyTooLong));
+        assert_eq!(
+            policy.validate(b"ab c"),
+            Err(ErrorReason::InvalidBodyCharset)
+        );
+        assert_eq!(
+            policy.validate(&[0xff, 0xfe, 0xfd]),
+            Err(ErrorReason::InvalidBodyEncoding)
+        );
+
+        let printable = ContentPolicy {
+            charset: Charset::Printable,
+            ..policy
+        };
+        assert_eq!(printable.validate(b"a b!"), Ok(()));
+        assert_eq!(printable.validate(b"a\\nb"), Ok(()));
+        assert_eq!(
+            printable.validate(b"a\nb"),
+            Err(ErrorReason::InvalidBodyCharset)
+        );
+    }
+}